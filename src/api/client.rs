@@ -21,6 +21,7 @@ type HmacSha256 = Hmac<Sha256>;
 pub struct YandexMusicClient {
     c: Client,
     pub login: String,
+    pub uid: String,
     token: String,
 }
 
@@ -38,6 +39,7 @@ impl YandexMusicClient {
             c,
             token: format!("OAuth {}", token),
             login: String::new(),
+            uid: String::new(),
         };
 
         let user_info = yandex_client.get_user_info()?;
@@ -46,6 +48,7 @@ impl YandexMusicClient {
         }
 
         yandex_client.login = user_info.login;
+        yandex_client.uid = user_info.uid;
         Ok(yandex_client)
     }
 
@@ -187,6 +190,42 @@ impl YandexMusicClient {
         Ok(meta.result)
     }
 
+    // Newest first.
+    pub fn get_liked_track_ids(&mut self) -> Result<Vec<String>, Box<dyn Error>> {
+        let url = format!("{}/users/{}/likes/tracks", BASE_URL, self.uid);
+
+        let resp = self.c.get(url)
+            .header(AUTHORIZATION, &self.token)
+            .header("X-Yandex-Music-Client", YANDEX_USER_AGENT)
+            .send()?;
+
+        resp.error_for_status_ref()?;
+
+        let meta: LikesMeta = resp.json()?;
+        Ok(meta.result.library.tracks.into_iter().map(|t| t.id).collect())
+    }
+
+    pub fn get_tracks(&mut self, track_ids: &[String]) -> Result<Vec<PlaylistTrack>, Box<dyn Error>> {
+        let url = format!("{}/tracks", BASE_URL);
+        let ids = track_ids.join(",");
+
+        let params: HashMap<&str, &str> = HashMap::from([
+            ("track-ids", ids.as_str()),
+            ("with-positions", "false"),
+        ]);
+
+        let resp = self.c.post(url)
+            .header(AUTHORIZATION, &self.token)
+            .header("X-Yandex-Music-Client", YANDEX_USER_AGENT)
+            .form(&params)
+            .send()?;
+
+        resp.error_for_status_ref()?;
+
+        let meta: TracksMeta = resp.json()?;
+        Ok(meta.result)
+    }
+
     pub fn get_artist_meta(&mut self, artist_id: &str) -> Result<ArtistMetaResult, Box<dyn Error>> {
         let url = format!("{}/artists/{}", BASE_URL, artist_id);
 
@@ -201,6 +240,28 @@ impl YandexMusicClient {
         Ok(meta.result)
     }
 
+    pub fn search_tracks(&mut self, query: &str) -> Result<Vec<SearchTrack>, Box<dyn Error>> {
+        let url = format!("{}/search", BASE_URL);
+
+        let params: HashMap<&str, &str> = HashMap::from([
+            ("text", query),
+            ("type", "track"),
+            ("page", "0"),
+            ("nocorrect", "true"),
+        ]);
+
+        let resp = self.c.get(url)
+            .header(AUTHORIZATION, &self.token)
+            .header("X-Yandex-Music-Client", YANDEX_USER_AGENT)
+            .query(&params)
+            .send()?;
+
+        resp.error_for_status_ref()?;
+
+        let meta: SearchMeta = resp.json()?;
+        Ok(meta.result.tracks.map(|t| t.results).unwrap_or_default())
+    }
+
     pub fn get_file_resp(&mut self, url: &str, with_range: bool) -> Result<ReqwestResp, ReqwestErr> {
         let mut req = self.c.get(url);
         if with_range {
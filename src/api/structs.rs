@@ -1,10 +1,27 @@
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer, Serialize};
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawId {
+    Num(u64),
+    Str(String),
+}
+
+// Yandex returns IDs as numbers in some responses and as strings in others.
+fn de_id<'de, D: Deserializer<'de>>(d: D) -> Result<String, D::Error> {
+    Ok(match RawId::deserialize(d)? {
+        RawId::Num(n) => n.to_string(),
+        RawId::Str(s) => s,
+    })
+}
 
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct UserInfoResult {
     pub has_plus: bool,
     pub login: String,
+    #[serde(deserialize_with = "de_id")]
+    pub uid: String,
 }
 
 #[derive(Deserialize)]
@@ -12,7 +29,7 @@ pub struct UserInfo {
     pub result: UserInfoResult,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct LyricsInfo {
     pub has_available_sync_lyrics: bool,
@@ -30,7 +47,7 @@ impl LyricsInfo {
         }
     }
 }
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Volume {
     pub artists: Vec<Artist>,
@@ -39,21 +56,29 @@ pub struct Volume {
     pub available: bool,
     pub lyrics_info: Option<LyricsInfo>,
     pub version: Option<String>,
+    pub duration_ms: Option<u64>,
+    pub content_warning: Option<String>,
+    pub isrc: Option<String>,
+    pub bpm: Option<f64>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 pub struct Artist {
+    #[serde(default, deserialize_with = "de_id")]
+    pub id: String,
     pub name: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 pub struct Label {
     pub name: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct AlbumResult {
+    #[serde(deserialize_with = "de_id")]
+    pub id: String,
     pub title: String,
     pub artists: Vec<Artist>,
     pub available: bool,
@@ -63,6 +88,11 @@ pub struct AlbumResult {
     pub version: Option<String>,
     pub volumes: Vec<Vec<Volume>>,
     pub year: Option<u16>,
+    pub release_date: Option<String>,
+    pub original_release_year: Option<u16>,
+    #[serde(rename = "type")]
+    pub album_type: Option<String>,
+    pub content_warning: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -117,11 +147,17 @@ pub struct PlaylistTrack {
     pub cover_uri: Option<String>,
     pub version: Option<String>,
     pub track_source: String,
+    pub duration_ms: Option<u64>,
+    pub content_warning: Option<String>,
+    pub isrc: Option<String>,
+    pub bpm: Option<f64>,
 }
 
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct AlbumResultInPlaylist {
+    #[serde(deserialize_with = "de_id")]
+    pub id: String,
     pub title: String,
     pub artists: Vec<Artist>,
     pub available: bool,
@@ -129,6 +165,37 @@ pub struct AlbumResultInPlaylist {
     pub labels: Vec<Label>,
     pub version: Option<String>,
     pub year: Option<u16>,
+    pub release_date: Option<String>,
+    pub original_release_year: Option<u16>,
+    #[serde(rename = "type")]
+    pub album_type: Option<String>,
+    pub content_warning: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TracksMeta {
+    pub result: Vec<PlaylistTrack>,
+}
+
+#[derive(Deserialize)]
+pub struct LikedTrack {
+    #[serde(deserialize_with = "de_id")]
+    pub id: String,
+}
+
+#[derive(Deserialize)]
+pub struct LikesLibrary {
+    pub tracks: Vec<LikedTrack>,
+}
+
+#[derive(Deserialize)]
+pub struct LikesResult {
+    pub library: LikesLibrary,
+}
+
+#[derive(Deserialize)]
+pub struct LikesMeta {
+    pub result: LikesResult,
 }
 
 #[derive(Deserialize)]
@@ -153,11 +220,41 @@ pub struct PlaylistMeta {
 #[derive(Deserialize)]
 pub struct ArtistMetaAlbum {
     pub id: u64,
+    #[serde(default)]
+    pub title: String,
+}
+
+#[derive(Deserialize)]
+pub struct ArtistCover {
+    pub uri: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ArtistDescription {
+    pub text: String,
+}
+
+#[derive(Deserialize)]
+pub struct ArtistLink {
+    pub title: String,
+    pub href: String,
+    #[serde(rename = "type")]
+    pub link_type: Option<String>,
 }
 
 #[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct ArtistMetaArtist {
+    #[serde(default, deserialize_with = "de_id")]
+    pub id: String,
     pub name: String,
+    pub cover: Option<ArtistCover>,
+    pub og_image: Option<String>,
+    #[serde(default)]
+    pub genres: Vec<String>,
+    pub description: Option<ArtistDescription>,
+    #[serde(default)]
+    pub links: Vec<ArtistLink>,
 }
 
 #[derive(Deserialize)]
@@ -181,4 +278,36 @@ pub struct OtherUserPlaylistMetaResult {
 #[derive(Deserialize)]
 pub struct OtherUserPlaylistMeta {
     pub result: OtherUserPlaylistMetaResult,
+}
+#[derive(Deserialize)]
+pub struct SearchAlbum {
+    #[serde(deserialize_with = "de_id")]
+    pub id: String,
+    pub title: String,
+    pub version: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct SearchTrack {
+    #[serde(deserialize_with = "de_id")]
+    pub id: String,
+    pub title: String,
+    pub version: Option<String>,
+    #[serde(default)]
+    pub albums: Vec<SearchAlbum>,
+}
+
+#[derive(Deserialize)]
+pub struct SearchTracks {
+    pub results: Vec<SearchTrack>,
+}
+
+#[derive(Deserialize)]
+pub struct SearchResult {
+    pub tracks: Option<SearchTracks>,
+}
+
+#[derive(Deserialize)]
+pub struct SearchMeta {
+    pub result: SearchResult,
 }
\ No newline at end of file
@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::models::DownloadFormat;
+use crate::tags::YANDEX_TRACK_ID;
+use crate::{metadata, tags, utils};
+
+// Remembers which tracks were downloaded, so they're skipped even after templates
+// change or files are moved or deleted. One "track_id<TAB>quality<TAB>path" line per
+// track; later lines win. Without a path configured nothing is skipped, but tracks
+// saved in this run are still remembered in memory for deduplication.
+pub struct DownloadArchive {
+    path: Option<PathBuf>,
+    entries: HashMap<String, (DownloadFormat, PathBuf)>,
+    skip_archived: bool,
+}
+
+impl DownloadArchive {
+    pub fn open(path: Option<&Path>) -> Result<DownloadArchive, Box<dyn Error>> {
+        let mut entries = HashMap::new();
+        if let Some(path) = path.filter(|p| p.exists()) {
+            for line in fs::read_to_string(path)?.lines() {
+                let mut parts = line.splitn(3, '\t');
+                let (Some(track_id), Some(quality), Some(file)) = (parts.next(), parts.next(), parts.next()) else {
+                    continue;
+                };
+                if let Some(format) = DownloadFormat::from_api_str(quality) {
+                    entries.insert(track_id.to_string(), (format, PathBuf::from(file)));
+                }
+            }
+        }
+        Ok(DownloadArchive { path: path.map(Path::to_path_buf), entries, skip_archived: true })
+    }
+
+    // Sync keeps its folders complete, so archived tracks are linked or downloaded
+    // into them again rather than skipped. New downloads are still recorded.
+    pub fn disable_skipping(&mut self) {
+        self.skip_archived = false;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.path.is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    // Returns where the track was saved if it's archived at this quality or better.
+    pub fn get(&self, track_id: &str, format: DownloadFormat) -> Option<&PathBuf> {
+        if !self.is_enabled() || !self.skip_archived {
+            return None;
+        }
+        self.entries.get(track_id)
+            .filter(|(archived, _)| *archived >= format)
+            .map(|(_, path)| path)
+    }
+
+    // Yandex doesn't offer every track in every format, so one archived at the best
+    // quality it's offered in counts as well.
+    pub fn get_offered(&self, track_id: &str, format: DownloadFormat, offered: DownloadFormat) -> Option<&PathBuf> {
+        let wanted = if offered < format { offered } else { format };
+        self.get(track_id, wanted)
+    }
+
+    // The archived quality of a track, if it was archived as this file.
+    pub fn quality_of(&self, track_id: &str, file: &Path) -> Option<DownloadFormat> {
+        self.entries.get(track_id)
+            .filter(|(_, path)| path == file)
+            .map(|(format, _)| *format)
+    }
+
+    // An existing file of the track at this quality or better, archived or not.
+    pub fn find_copy(&self, track_id: &str, format: DownloadFormat) -> Option<&PathBuf> {
+        self.entries.get(track_id)
+            .filter(|(archived, path)| *archived >= format && path.is_file())
+            .map(|(_, path)| path)
+    }
+
+    // Entries already archived as-is aren't written again.
+    pub fn add(&mut self, track_id: &str, format: DownloadFormat, file: &Path) -> Result<(), Box<dyn Error>> {
+        let entry = (format, file.to_path_buf());
+        if self.entries.get(track_id) == Some(&entry) {
+            return Ok(());
+        }
+        self.entries.insert(track_id.to_string(), entry);
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut f = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(f, "{}\t{}\t{}", track_id, format.as_api_str(), file.to_string_lossy())?;
+        Ok(())
+    }
+}
+
+fn read_track(path: &Path) -> Option<(String, DownloadFormat)> {
+    let codec = path.extension()
+        .and_then(|e| e.to_str())
+        .and_then(metadata::parse_codec_from_ext)?;
+    let track_id = tags::read_tags(&path.to_path_buf(), codec).ok()?
+        .get(YANDEX_TRACK_ID)
+        .cloned()?;
+    Some((track_id, tags::read_quality(&path.to_path_buf(), codec)?))
+}
+
+// Adds every library file with an embedded track ID to the archive.
+pub fn import_library(archive: &mut DownloadArchive, lib_path: &Path) -> Result<(), Box<dyn Error>> {
+    if !archive.is_enabled() {
+        return Err("No download archive configured! Set download_archive or --download-archive.".into());
+    }
+    let files = utils::find_audio_files(lib_path)?;
+    let before = archive.len();
+    let mut skipped = 0;
+
+    for path in files {
+        match read_track(&path) {
+            Some((track_id, format)) => archive.add(&track_id, format, &path)?,
+            None => {
+                println!("No embedded track ID: {:?}", path);
+                skipped += 1;
+            }
+        }
+    }
+
+    println!("\nImported {} tracks, {} files skipped.", archive.len() - before, skipped);
+    Ok(())
+}
+
+// Without an archive file, deduplication learns what's in the library from the
+// IDs embedded in its files. Nothing is written to disk.
+pub fn index_library(archive: &mut DownloadArchive, lib_path: &Path) -> Result<(), Box<dyn Error>> {
+    if !lib_path.exists() {
+        return Ok(());
+    }
+    println!("Indexing library for deduplication...");
+    for path in utils::find_audio_files(lib_path)? {
+        // Skips .trash, .replaced and the like.
+        let hidden = path.strip_prefix(lib_path).unwrap_or(&path).components()
+            .any(|part| part.as_os_str().to_string_lossy().starts_with('.'));
+        if hidden {
+            continue;
+        }
+        if let Some((track_id, format)) = read_track(&path) {
+            archive.add(&track_id, format, &path)?;
+        }
+    }
+    println!("{} tracks indexed.", archive.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_with(dir: &Path, contents: &str) -> DownloadArchive {
+        let path = dir.join("archive.txt");
+        fs::write(&path, contents).unwrap();
+        DownloadArchive::open(Some(&path)).unwrap()
+    }
+
+    #[test]
+    fn later_lines_win_and_bad_lines_are_skipped() {
+        let dir = utils::test_dir("archive-parse");
+        let archive = open_with(&dir, "1\tlq\ta.m4a\n1\tlossless\tb.flac\n2\tbogus\tc.mp3\nno tabs\n3\thq\tsub\tdir.mp3\n");
+        assert_eq!(archive.len(), 2);
+        assert_eq!(archive.get("1", DownloadFormat::Flac), Some(&PathBuf::from("b.flac")));
+        assert_eq!(archive.get("2", DownloadFormat::Aac64), None);
+        assert_eq!(archive.get("3", DownloadFormat::Hq), Some(&PathBuf::from("sub\tdir.mp3")));
+    }
+
+    #[test]
+    fn only_equal_or_better_quality_counts() {
+        let dir = utils::test_dir("archive-quality");
+        let archive = open_with(&dir, "1\thq\ta.mp3\n");
+        assert!(archive.get("1", DownloadFormat::Aac192).is_some());
+        assert!(archive.get("1", DownloadFormat::Hq).is_some());
+        assert!(archive.get("1", DownloadFormat::Flac).is_none());
+        assert!(archive.get("2", DownloadFormat::Aac64).is_none());
+    }
+
+    #[test]
+    fn best_offered_quality_counts() {
+        let dir = utils::test_dir("archive-offered");
+        let archive = open_with(&dir, "1\thq\ta.mp3\n2\tnq\tb.m4a\n");
+        assert!(archive.get_offered("1", DownloadFormat::Flac, DownloadFormat::Hq).is_some());
+        assert!(archive.get_offered("1", DownloadFormat::Flac, DownloadFormat::Flac).is_none());
+        assert!(archive.get_offered("2", DownloadFormat::Flac, DownloadFormat::Hq).is_none());
+        assert!(archive.get_offered("2", DownloadFormat::Aac192, DownloadFormat::Flac).is_some());
+    }
+
+    #[test]
+    fn nothing_is_skipped_without_a_file() {
+        let mut archive = DownloadArchive::open(None).unwrap();
+        archive.add("1", DownloadFormat::Flac, Path::new("a.flac")).unwrap();
+        assert!(!archive.is_enabled());
+        assert!(archive.get("1", DownloadFormat::Aac64).is_none());
+        // Still remembered for deduplication.
+        assert_eq!(archive.quality_of("1", Path::new("a.flac")), Some(DownloadFormat::Flac));
+    }
+
+    #[test]
+    fn added_entries_survive_reopening() {
+        let dir = utils::test_dir("archive-add");
+        let path = dir.join("archive.txt");
+        let mut archive = DownloadArchive::open(Some(&path)).unwrap();
+        archive.add("1", DownloadFormat::Hq, Path::new("a.mp3")).unwrap();
+        archive.add("1", DownloadFormat::Flac, Path::new("b.flac")).unwrap();
+        assert_eq!(archive.get("1", DownloadFormat::Flac), Some(&PathBuf::from("b.flac")));
+
+        let reopened = DownloadArchive::open(Some(&path)).unwrap();
+        assert_eq!(reopened.len(), 1);
+        assert_eq!(reopened.get("1", DownloadFormat::Flac), Some(&PathBuf::from("b.flac")));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "1\thq\ta.mp3\n1\tlossless\tb.flac\n");
+    }
+
+    #[test]
+    fn unchanged_entries_are_not_written_again() {
+        let dir = utils::test_dir("archive-unchanged");
+        let path = dir.join("archive.txt");
+        let mut archive = DownloadArchive::open(Some(&path)).unwrap();
+        archive.add("1", DownloadFormat::Hq, Path::new("a.mp3")).unwrap();
+        archive.add("1", DownloadFormat::Hq, Path::new("a.mp3")).unwrap();
+        archive.add("1", DownloadFormat::Hq, Path::new("moved/a.mp3")).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "1\thq\ta.mp3\n1\thq\tmoved/a.mp3\n");
+    }
+}
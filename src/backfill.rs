@@ -0,0 +1,152 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use crate::api::client::YandexMusicClient;
+use crate::api::structs::AlbumResult;
+use crate::models::{MediaLink, Settings};
+use crate::tags::YANDEX_TRACK_ID;
+use crate::{metadata, processor, retag, tags, utils, verify};
+
+// Adds lyrics to files that were downloaded without them. With a library path every
+// file in it is handled, otherwise the tracks of the given URLs are looked up in out_path
+// by their embedded IDs.
+pub fn backfill_lyrics(
+    c: &mut YandexMusicClient,
+    settings: &Settings,
+    lib_path: Option<&PathBuf>,
+) -> Result<(), Box<dyn Error>> {
+    if !settings.write_lyrics && !settings.embed_lyrics {
+        return Err("Nothing to do: lyrics are neither embedded nor written to files".into());
+    }
+
+    let scan_path = lib_path.unwrap_or(&settings.out_path);
+    let mut files = utils::find_audio_files(scan_path)?;
+
+    if lib_path.is_none() {
+        let mut wanted = HashSet::new();
+        for link in &settings.media_links {
+            match collect_track_ids(c, link) {
+                Ok(ids) => wanted.extend(ids),
+                Err(e) => eprintln!("Error processing link: {}", e),
+            }
+        }
+        files = filter_by_track_ids(files, &mut wanted);
+        for track_id in wanted {
+            println!("Track {} not found in {:?}.", track_id, scan_path);
+        }
+    }
+
+    if files.is_empty() {
+        return Err("No audio files to process".into());
+    }
+
+    let mut albums: HashMap<String, AlbumResult> = HashMap::new();
+    let mut no_lyrics = Vec::new();
+    let total = files.len();
+
+    for (i, path) in files.iter().enumerate() {
+        println!("File {} of {}: {:?}", i + 1, total, path);
+        match backfill_file(c, settings, scan_path, path, &mut albums) {
+            Ok(true) => {}
+            Ok(false) => no_lyrics.push(path),
+            Err(e) => eprintln!("Failed: {}", e),
+        }
+    }
+
+    if !no_lyrics.is_empty() {
+        println!("\nNo lyrics available for {} tracks:", no_lyrics.len());
+        for path in no_lyrics {
+            println!("  {:?}", path);
+        }
+    }
+    Ok(())
+}
+
+// Returns false if Yandex has no lyrics for the track, API failures are errors.
+fn backfill_file(
+    c: &mut YandexMusicClient,
+    settings: &Settings,
+    lib_path: &Path,
+    path: &Path,
+    albums: &mut HashMap<String, AlbumResult>,
+) -> Result<bool, Box<dyn Error>> {
+    let path = path.to_path_buf();
+    let codec = path.extension()
+        .and_then(|e| e.to_str())
+        .and_then(metadata::parse_codec_from_ext)
+        .ok_or("unsupported file type")?;
+
+    let old = tags::read_tags(&path, codec)?;
+    let (album_id, track_id) = retag::identify_file(c, settings, lib_path, &path, &old, albums)?;
+    let album = retag::get_album_cached(c, settings, &album_id, albums)?;
+    let mut meta = retag::build_track_meta(settings, album, &track_id)
+        .ok_or("track is no longer part of its album")?;
+
+    processor::fetch_lyrics(c, &track_id, &mut meta)
+        .map_err(|e| format!("failed to fetch lyrics: {}", e))?;
+    if meta.timed_lyrics.is_none() && meta.untimed_lyrics.is_none() {
+        return Ok(false);
+    }
+
+    if settings.write_lyrics {
+        processor::write_lyrics_files(&path, &meta, settings);
+    }
+    processor::prepare_embedded_lyrics(&mut meta, settings);
+    if settings.embed_lyrics {
+        tags::write_lyrics(&path, codec, &meta)?;
+        if let Err(e) = verify::update_manifest(&path, &path) {
+            eprintln!("Warning: Failed to update checksum manifest: {}", e);
+        }
+        println!("Lyrics embedded.");
+    }
+    Ok(true)
+}
+
+fn collect_track_ids(c: &mut YandexMusicClient, link: &MediaLink) -> Result<Vec<String>, Box<dyn Error>> {
+    let ids = match link {
+        MediaLink::Album { album_id } => album_track_ids(&c.get_album_meta(album_id)?),
+        MediaLink::Track { track_id, .. } => vec![track_id.clone()],
+        MediaLink::Artist { artist_id } => {
+            let mut ids = Vec::new();
+            for album in c.get_artist_meta(artist_id)?.albums {
+                ids.extend(album_track_ids(&c.get_album_meta(&album.id.to_string())?));
+            }
+            ids
+        }
+        MediaLink::Playlist { uuid_or_login } => {
+            let playlist = c.get_other_user_playlist_meta(uuid_or_login)?;
+            let meta = c.get_playlist_meta(&playlist.playlist_uuid)?;
+            meta.tracks.into_iter().map(|t| t.track.id).collect()
+        }
+    };
+    Ok(ids)
+}
+
+fn album_track_ids(album: &AlbumResult) -> Vec<String> {
+    album.volumes.iter().flatten().map(|t| t.id.clone()).collect()
+}
+
+// Keeps files whose embedded track ID was asked for, removing found IDs from `wanted`.
+fn filter_by_track_ids(files: Vec<PathBuf>, wanted: &mut HashSet<String>) -> Vec<PathBuf> {
+    let mut found = HashSet::new();
+    let files = files.into_iter()
+        .filter(|path| {
+            let codec = path.extension()
+                .and_then(|e| e.to_str())
+                .and_then(metadata::parse_codec_from_ext);
+            let track_id = codec
+                .and_then(|codec| tags::read_tags(path, codec).ok())
+                .and_then(|summary| summary.get(YANDEX_TRACK_ID).cloned());
+            match track_id {
+                Some(id) if wanted.contains(&id) => {
+                    found.insert(id);
+                    true
+                }
+                _ => false,
+            }
+        })
+        .collect();
+    wanted.retain(|id| !found.contains(id));
+    files
+}
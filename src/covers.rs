@@ -0,0 +1,118 @@
+use std::error::Error;
+use std::io::Cursor;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat};
+
+const JPEG_QUALITIES: [u8; 5] = [90, 80, 70, 60, 50];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoverType {
+    Jpeg,
+    Png,
+    Webp,
+    Unknown,
+}
+
+impl CoverType {
+    pub fn mime(&self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::Png => "image/png",
+            Self::Webp => "image/webp",
+            Self::Unknown => "application/octet-stream",
+        }
+    }
+
+    // Only JPEG and PNG can be embedded in every tag format.
+    pub fn is_embeddable(&self) -> bool {
+        matches!(self, Self::Jpeg | Self::Png)
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::Webp => "webp",
+            _ => "jpg",
+        }
+    }
+}
+
+// The avatars host doesn't always serve JPEGs, so sniff the magic bytes.
+pub fn detect_type(data: &[u8]) -> CoverType {
+    if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        CoverType::Jpeg
+    } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        CoverType::Png
+    } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        CoverType::Webp
+    } else {
+        CoverType::Unknown
+    }
+}
+
+fn encode_jpeg(img: &DynamicImage, quality: u8) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut buf = Vec::new();
+    let encoder = JpegEncoder::new_with_quality(&mut buf, quality);
+    img.to_rgb8().write_with_encoder(encoder)?;
+    Ok(buf)
+}
+
+// Shrinks and recompresses cover art for embedding. JPEG and PNG covers already within
+// the limits are returned untouched, anything else is converted to JPEG or rejected
+// if it can't be decoded.
+pub fn prepare_for_embedding(
+    data: Vec<u8>,
+    max_size: Option<u32>,
+    max_kb: Option<u32>,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let cover_type = detect_type(&data);
+    let max_bytes = max_kb.map(|kb| kb as usize * 1024);
+
+    let mut img = match cover_type {
+        CoverType::Jpeg => image::load_from_memory_with_format(&data, ImageFormat::Jpeg)?,
+        CoverType::Png => image::load_from_memory_with_format(&data, ImageFormat::Png)?,
+        CoverType::Webp => image::load_from_memory_with_format(&data, ImageFormat::WebP)?,
+        CoverType::Unknown => image::load_from_memory(&data)?,
+    };
+
+    let too_wide = max_size.is_some_and(|m| img.width() > m || img.height() > m);
+    let too_heavy = max_bytes.is_some_and(|m| data.len() > m);
+    if !too_wide && !too_heavy && cover_type.is_embeddable() {
+        return Ok(data);
+    }
+
+    if let Some(m) = max_size {
+        if too_wide {
+            img = img.resize(m, m, FilterType::Lanczos3);
+        }
+    }
+
+    // PNGs that only needed resizing stay PNG.
+    if cover_type == CoverType::Png && !too_heavy {
+        let mut buf = Vec::new();
+        img.write_to(&mut Cursor::new(&mut buf), ImageFormat::Png)?;
+        if max_bytes.is_none_or(|m| buf.len() <= m) {
+            return Ok(buf);
+        }
+    }
+
+    let Some(max_bytes) = max_bytes else {
+        return encode_jpeg(&img, JPEG_QUALITIES[0]);
+    };
+
+    // Lower the quality first, then the resolution.
+    loop {
+        for quality in JPEG_QUALITIES {
+            let buf = encode_jpeg(&img, quality)?;
+            if buf.len() <= max_bytes {
+                return Ok(buf);
+            }
+        }
+        if img.width() <= 100 {
+            return encode_jpeg(&img, JPEG_QUALITIES[JPEG_QUALITIES.len() - 1]);
+        }
+        img = img.resize(img.width() * 4 / 5, img.height() * 4 / 5, FilterType::Lanczos3);
+    }
+}
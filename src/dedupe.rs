@@ -0,0 +1,105 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::models::LinkMode;
+use crate::utils;
+
+// Creates `target` from an existing file. Hard links fall back to copies across
+// drives and symlinks where the OS doesn't allow them. Returns the mode used.
+pub fn link_file(existing: &Path, target: &Path, mode: LinkMode) -> io::Result<LinkMode> {
+    let linked = match mode {
+        LinkMode::Off | LinkMode::Copy => return fs::copy(existing, target).map(|_| LinkMode::Copy),
+        LinkMode::Hardlink => fs::hard_link(existing, target),
+        LinkMode::Symlink => symlink(existing, target),
+    };
+    match linked {
+        Ok(()) => Ok(mode),
+        Err(e) => {
+            eprintln!("Warning: Couldn't link {:?}, copying instead: {}", target, e);
+            fs::copy(existing, target)?;
+            Ok(LinkMode::Copy)
+        }
+    }
+}
+
+// Relative, so the library can be moved as a whole.
+fn symlink(existing: &Path, target: &Path) -> io::Result<()> {
+    let existing = fs::canonicalize(existing)?;
+    let target_dir = target.parent()
+        .map(fs::canonicalize)
+        .transpose()?
+        .unwrap_or_default();
+    let rel = utils::relative_path(&target_dir, &existing);
+
+    #[cfg(unix)]
+    return std::os::unix::fs::symlink(rel, target);
+    #[cfg(windows)]
+    return std::os::windows::fs::symlink_file(rel, target);
+    #[cfg(not(any(unix, windows)))]
+    return Err(io::Error::new(io::ErrorKind::Unsupported, "symlinks aren't supported"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn existing_file(name: &str) -> (PathBuf, PathBuf) {
+        let dir = utils::test_dir(name);
+        let existing = dir.join("existing.flac");
+        fs::write(&existing, b"audio").unwrap();
+        (dir, existing)
+    }
+
+    #[test]
+    fn off_and_copy_make_copies() {
+        let (dir, existing) = existing_file("dedupe-copy");
+        for (i, mode) in [LinkMode::Off, LinkMode::Copy].into_iter().enumerate() {
+            let target = dir.join(format!("{}.flac", i));
+            assert_eq!(link_file(&existing, &target, mode).unwrap(), LinkMode::Copy);
+            assert_eq!(fs::read(&target).unwrap(), b"audio");
+            assert!(!fs::symlink_metadata(&target).unwrap().file_type().is_symlink());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hard_links_share_the_file() {
+        use std::os::unix::fs::MetadataExt;
+        let (dir, existing) = existing_file("dedupe-hardlink");
+        let target = dir.join("linked.flac");
+        assert_eq!(link_file(&existing, &target, LinkMode::Hardlink).unwrap(), LinkMode::Hardlink);
+        assert_eq!(fs::metadata(&target).unwrap().ino(), fs::metadata(&existing).unwrap().ino());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_are_relative() {
+        let (dir, existing) = existing_file("dedupe-symlink");
+        fs::create_dir_all(dir.join("playlist")).unwrap();
+        let target = dir.join("playlist").join("linked.flac");
+        assert_eq!(link_file(&existing, &target, LinkMode::Symlink).unwrap(), LinkMode::Symlink);
+        assert_eq!(fs::read_link(&target).unwrap(), Path::new("..").join("existing.flac"));
+        assert_eq!(fs::read(&target).unwrap(), b"audio");
+    }
+
+    #[test]
+    fn falls_back_to_a_copy_when_linking_fails() {
+        let (dir, existing) = existing_file("dedupe-fallback");
+        for mode in [LinkMode::Hardlink, LinkMode::Symlink] {
+            // Linking refuses to replace a file, copying doesn't.
+            let target = dir.join("taken.flac");
+            fs::write(&target, b"old").unwrap();
+            assert_eq!(link_file(&existing, &target, mode).unwrap(), LinkMode::Copy);
+            assert_eq!(fs::read(&target).unwrap(), b"audio");
+        }
+    }
+
+    #[test]
+    fn fails_when_the_copy_does_too() {
+        let (dir, _) = existing_file("dedupe-missing");
+        let missing = dir.join("missing.flac");
+        assert!(link_file(&missing, &dir.join("target.flac"), LinkMode::Hardlink).is_err());
+    }
+}
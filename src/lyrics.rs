@@ -0,0 +1,161 @@
+use std::sync::OnceLock;
+
+use regex::Regex;
+
+use crate::models::ParsedAlbumMeta;
+
+fn time_tag_regex() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| {
+        Regex::new(r"^\[(\d+):(\d{1,2})(?:[.:](\d{1,3}))?\]").unwrap()
+    })
+}
+
+fn parse_time_tag(min: &str, sec: &str, frac: Option<&str>) -> Option<u32> {
+    let min: u32 = min.parse().ok()?;
+    let sec: u32 = sec.parse().ok()?;
+    // ".5" = 500 ms, ".05" = 50 ms, ".005" = 5 ms.
+    let ms = match frac {
+        Some(f) => f.parse::<u32>().ok()? * 10u32.pow(3 - f.len() as u32),
+        None => 0,
+    };
+    // Absurd minute counts would overflow; such tags are dropped.
+    min.checked_mul(60_000)?.checked_add(sec * 1000 + ms)
+}
+
+// Parses LRC text into (milliseconds, line) pairs sorted by time.
+// Lines with several time tags are repeated for each of them,
+// header tags like [ar:...] and untimed lines are skipped.
+pub fn parse_lrc(text: &str) -> Vec<(u32, String)> {
+    let re = time_tag_regex();
+    let mut lines = Vec::new();
+
+    for line in text.lines() {
+        let mut rest = line.trim();
+        let mut times = Vec::new();
+
+        while let Some(cap) = re.captures(rest) {
+            if let Some(ms) = parse_time_tag(&cap[1], &cap[2], cap.get(3).map(|m| m.as_str())) {
+                times.push(ms);
+            }
+            rest = &rest[cap[0].len()..];
+        }
+
+        for ms in times {
+            lines.push((ms, rest.trim().to_string()));
+        }
+    }
+
+    lines.sort_by_key(|(ms, _)| *ms);
+    lines
+}
+
+// Strips LRC time tags, leaving plain text. Non-LRC text is returned as is.
+pub fn lrc_to_plain(text: &str) -> String {
+    let lines = parse_lrc(text);
+    if lines.is_empty() {
+        return text.to_string();
+    }
+    lines.into_iter().map(|(_, l)| l).collect::<Vec<String>>().join("\n")
+}
+
+// Yandex doesn't report the lyrics language, so guess the ISO 639-2 code from the script.
+pub fn detect_language(text: &str) -> &'static str {
+    let mut cyrillic = 0;
+    let mut latin = 0;
+    for c in text.chars() {
+        match c {
+            'А'..='я' | 'Ё' | 'ё' => cyrillic += 1,
+            'A'..='Z' | 'a'..='z' => latin += 1,
+            _ => {}
+        }
+    }
+    if cyrillic == 0 && latin == 0 {
+        "und"
+    } else if cyrillic >= latin {
+        "rus"
+    } else {
+        "eng"
+    }
+}
+
+fn format_lrc_length(ms: u64) -> String {
+    let secs = ms / 1000;
+    format!("{:02}:{:02}.{:02}", secs / 60, secs % 60, (ms % 1000) / 10)
+}
+
+// Prepends [ar:], [ti:], [al:] and [length:] ID tags to LRC text.
+pub fn add_lrc_headers(text: &str, meta: &ParsedAlbumMeta) -> String {
+    let mut headers = String::new();
+    for (tag, value) in [("ar", &meta.artist), ("ti", &meta.title), ("al", &meta.album_title)] {
+        if !value.is_empty() {
+            headers.push_str(&format!("[{}:{}]\n", tag, value));
+        }
+    }
+    if let Some(ms) = meta.duration_ms {
+        headers.push_str(&format!("[length:{}]\n", format_lrc_length(ms)));
+    }
+    headers + text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_fractions_by_their_length() {
+        let lines = parse_lrc("[00:01.5]a\n[00:02.05]b\n[00:03.005]c\n[00:04:20]d\n[01:05]e");
+        assert_eq!(lines, vec![
+            (1500, "a".to_string()),
+            (2050, "b".to_string()),
+            (3005, "c".to_string()),
+            (4200, "d".to_string()),
+            (65000, "e".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn drops_tags_that_overflow() {
+        assert_eq!(parse_lrc("[99999:00.00]Late\n[4294967295:00]Later\n[00:01.00]Early"), vec![(1000, "Early".to_string())]);
+        assert_eq!(parse_time_tag("71582", "00", None), Some(4_294_920_000));
+    }
+
+    #[test]
+    fn repeats_lines_with_several_tags_and_sorts() {
+        let lines = parse_lrc("[00:10.00][00:30.00]Chorus\n[00:20.00]Verse");
+        assert_eq!(lines, vec![
+            (10000, "Chorus".to_string()),
+            (20000, "Verse".to_string()),
+            (30000, "Chorus".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn skips_headers_and_untimed_lines() {
+        let lines = parse_lrc("[ar:Artist]\n[ti:Title]\nno time\n\n  [00:01.00]  Line  \r\n[00:02.00]");
+        assert_eq!(lines, vec![(1000, "Line".to_string()), (2000, String::new())]);
+    }
+
+    #[test]
+    fn keeps_brackets_inside_the_text() {
+        assert_eq!(parse_lrc("[00:01.00]Intro [x2]"), vec![(1000, "Intro [x2]".to_string())]);
+    }
+
+    #[test]
+    fn plain_text_passes_through() {
+        assert_eq!(lrc_to_plain("just words\nmore"), "just words\nmore");
+        assert_eq!(lrc_to_plain("[00:02.00]b\n[00:01.00]a"), "a\nb");
+    }
+
+    #[test]
+    fn formats_length_header() {
+        assert_eq!(format_lrc_length(65_432), "01:05.43");
+    }
+
+    #[test]
+    fn guesses_language_from_script() {
+        assert_eq!(detect_language("Привет, world"), "rus");
+        assert_eq!(detect_language("Hello, мир"), "eng");
+        assert_eq!(detect_language("123 !"), "und");
+    }
+}
@@ -1,22 +1,42 @@
 use clap::Parser;
 use std::error::Error;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::thread;
 use std::time::Duration;
 
 use crate::api::client::YandexMusicClient;
-use crate::models::{DownloadFormat, MediaLink, Settings};
-use crate::structs::{CliArgs, FileConfig};
+use crate::archive::DownloadArchive;
+use crate::models::{
+    DedupePolicy, DownloadFormat, FilenameCharset, FilenamePolicy, LinkMode, MediaLink, Mode, PlaylistLayout, Settings,
+    SortNameOptions, SourceKind, TranslitScheme,
+};
+use crate::structs::{CliArgs, CliCommand, FileConfig, ReportCommand};
 use crate::utils::resolve_ffmpeg_path;
 
 mod api;
+mod archive;
+mod backfill;
+mod covers;
+mod dedupe;
+mod lyrics;
 mod metadata;
 mod models;
+mod playlists;
 mod processor;
+mod replace;
+mod report;
+mod retag;
+mod sidecars;
 mod structs;
+mod sync;
 mod tags;
+mod template;
+mod translit;
+mod upgrade;
 mod utils;
+mod verify;
+mod watch;
 
 fn bootstrap() -> Result<Settings, Box<dyn Error>> {
     let cli = CliArgs::parse();
@@ -47,6 +67,10 @@ fn bootstrap() -> Result<Settings, Box<dyn Error>> {
         .or(file_cfg.out_path)
         .unwrap_or(PathBuf::from("downloads"));
 
+    let download_archive = cli.download_archive
+        .or(file_cfg.download_archive)
+        .filter(|p| !p.as_os_str().is_empty());
+
     let ffmpeg_path = resolve_ffmpeg_path(file_cfg.ffmpeg_path, &exe_path);
 
     let album_template = cli.album_template
@@ -55,11 +79,100 @@ fn bootstrap() -> Result<Settings, Box<dyn Error>> {
     let track_template = cli.track_template
         .or(file_cfg.track_template)
         .unwrap_or("{track_num_pad}. {title}".to_string());
+    template::validate("album_template", &album_template, utils::ALBUM_TEMPLATE_VARS)?;
+    let track_vars = [utils::ALBUM_TEMPLATE_VARS, utils::TRACK_ONLY_TEMPLATE_VARS].concat();
+    template::validate("track_template", &track_template, &track_vars)?;
+
+    let artist_separator = cli.artist_separator
+        .or(file_cfg.artist_separator)
+        .unwrap_or(", ".to_string());
+
+    let playlist_template = cli.playlist_template
+        .or(file_cfg.playlist_template)
+        .unwrap_or("{owner} - {playlist_name}".to_string());
+    template::validate("playlist_template", &playlist_template, utils::PLAYLIST_TEMPLATE_VARS)?;
+
+    let playlist_layout_raw = cli.playlist_layout
+        .or(file_cfg.playlist_layout)
+        .unwrap_or("flat".to_string());
+    let playlist_layout = PlaylistLayout::from_name(&playlist_layout_raw)
+        .ok_or("Invalid playlist_layout! Use flat or albums.")?;
 
     let keep_covers = cli.keep_covers || file_cfg.keep_covers.unwrap_or(false);
     let write_covers = cli.write_covers || file_cfg.write_covers.unwrap_or(false);
     let get_original_covers = cli.get_original_covers || file_cfg.get_original_covers.unwrap_or(false);
+    let cover_max_size = cli.cover_max_size.or(file_cfg.cover_max_size).filter(|s| *s > 0);
+    let cover_max_kb = cli.cover_max_kb.or(file_cfg.cover_max_kb).filter(|s| *s > 0);
     let write_lyrics = cli.write_lyrics || file_cfg.write_lyrics.unwrap_or(false);
+    let embed_lyrics = !cli.no_embed_lyrics && file_cfg.embed_lyrics.unwrap_or(true);
+    let lrc_headers = !cli.no_lrc_headers && file_cfg.lrc_headers.unwrap_or(true);
+    let plain_lyrics = cli.plain_lyrics || file_cfg.plain_lyrics.unwrap_or(false);
+    let disc_folders = cli.disc_folders || file_cfg.disc_folders.unwrap_or(false);
+    let write_artist_nfo = cli.write_artist_nfo || file_cfg.write_artist_nfo.unwrap_or(false);
+    let write_album_sidecars = cli.write_album_sidecars || file_cfg.write_album_sidecars.unwrap_or(false);
+    let write_m3u8 = cli.write_m3u8 || file_cfg.write_m3u8.unwrap_or(false);
+    let write_xspf = cli.write_xspf || file_cfg.write_xspf.unwrap_or(false);
+    let batch_playlist = cli.batch_playlist
+        .or(file_cfg.batch_playlist)
+        .filter(|p| !p.trim().is_empty());
+    let max_path_length = cli.max_path_length
+        .or(file_cfg.max_path_length)
+        .filter(|l| *l > 0)
+        .unwrap_or_else(utils::default_max_path_len);
+    let charset_raw = cli.filename_charset
+        .or(file_cfg.filename_charset)
+        .unwrap_or("unicode".to_string());
+    let charset = FilenameCharset::from_name(&charset_raw)
+        .ok_or("Invalid filename_charset! Use unicode, translit or ascii.")?;
+    let scheme_raw = cli.translit_scheme
+        .or(file_cfg.translit_scheme)
+        .unwrap_or("bgn".to_string());
+    let translit_scheme = TranslitScheme::from_name(&scheme_raw)
+        .ok_or("Invalid translit_scheme! Use gost or bgn.")?;
+    let replacement = cli.filename_replacement
+        .or(file_cfg.filename_replacement)
+        .unwrap_or("_".to_string());
+    utils::validate_replacement(&replacement, charset)?;
+    let filename_policy = FilenamePolicy {
+        charset,
+        translit_scheme,
+        replacement,
+        collapse_whitespace: cli.collapse_whitespace || file_cfg.collapse_whitespace.unwrap_or(false),
+        trim: !cli.no_trim_names && file_cfg.trim_names.unwrap_or(true),
+    };
+
+    let write_sort_tags = cli.write_sort_tags || file_cfg.write_sort_tags.unwrap_or(false);
+    let sort_names = write_sort_tags.then(|| SortNameOptions {
+        translit_scheme,
+        articles: file_cfg.sort_articles
+            .unwrap_or(vec!["The".to_string(), "A".to_string(), "An".to_string()]),
+        overrides: file_cfg.sort_overrides.unwrap_or_default(),
+    });
+
+    let parse_dedupe = |name: &str, value: Option<String>| -> Result<LinkMode, Box<dyn Error>> {
+        let value = value.unwrap_or("off".to_string());
+        LinkMode::from_name(&value)
+            .ok_or_else(|| format!("Invalid {}! Use off, hardlink, symlink or copy.", name).into())
+    };
+    let dedupe = DedupePolicy {
+        album: parse_dedupe("dedupe_album", cli.dedupe_album.or(file_cfg.dedupe_album))?,
+        artist: parse_dedupe("dedupe_artist", cli.dedupe_artist.or(file_cfg.dedupe_artist))?,
+        playlist: parse_dedupe("dedupe_playlist", cli.dedupe_playlist.or(file_cfg.dedupe_playlist))?,
+    };
+
+    let verify_downloads = cli.verify || file_cfg.verify_downloads.unwrap_or(false);
+
+    let watch_interval = cli.watch_interval
+        .or(file_cfg.watch_interval)
+        .filter(|m| *m > 0)
+        .unwrap_or(360);
+    let watch_webhook = cli.watch_webhook
+        .or(file_cfg.watch_webhook)
+        .filter(|u| !u.trim().is_empty());
+    let watch_state = cli.watch_state
+        .or(file_cfg.watch_state)
+        .filter(|p| !p.as_os_str().is_empty());
+
     let sleep = cli.sleep || file_cfg.sleep.unwrap_or(false);
 
     let processed_url_strings = utils::process_urls(&cli.urls)?;
@@ -72,7 +185,30 @@ fn bootstrap() -> Result<Settings, Box<dyn Error>> {
         }
     }
 
-    if media_links.is_empty() {
+    let mode = match cli.command {
+        Some(CliCommand::Retag { path, dry_run, yes }) => Mode::Retag { path, dry_run, assume_yes: yes },
+        Some(CliCommand::Lyrics { path }) => Mode::Lyrics { path },
+        Some(CliCommand::ArchiveImport { path }) => Mode::ImportArchive { path },
+        Some(CliCommand::Sync { delete, likes }) => Mode::Sync { delete_removed: delete, likes },
+        Some(CliCommand::Upgrade { path, dry_run, yes, delete }) => {
+            Mode::Upgrade { path, dry_run, assume_yes: yes, delete_old: delete }
+        }
+        Some(CliCommand::Verify { path, redownload, write_manifests }) => {
+            Mode::Verify { path, redownload, write_manifests }
+        }
+        Some(CliCommand::Report { report: ReportCommand::Missing { path, json } }) => Mode::ReportMissing { path, json },
+        Some(CliCommand::Watch { subscriptions, once, download_existing }) => {
+            Mode::Watch { subscriptions, once, download_existing }
+        }
+        None if cli.list_template_vars => Mode::ListTemplateVars,
+        None => Mode::Download,
+    };
+
+    let needs_urls = matches!(
+        mode,
+        Mode::Download | Mode::Lyrics { path: None } | Mode::ListTemplateVars | Mode::Sync { likes: false, .. }
+    );
+    if needs_urls && media_links.is_empty() {
         return Err("No valid URLs to process!".into());
     }
 
@@ -81,14 +217,38 @@ fn bootstrap() -> Result<Settings, Box<dyn Error>> {
         token,
         format,
         out_path,
+        download_archive,
         ffmpeg_path,
         keep_covers,
         write_covers,
         get_original_covers,
+        cover_max_size,
+        cover_max_kb,
         write_lyrics,
+        embed_lyrics,
+        lrc_headers,
+        plain_lyrics,
+        disc_folders,
+        write_artist_nfo,
+        write_album_sidecars,
         album_template,
         track_template,
+        artist_separator,
+        playlist_template,
+        playlist_layout,
+        write_m3u8,
+        write_xspf,
+        batch_playlist,
+        max_path_length,
+        filename_policy,
+        sort_names,
+        dedupe,
+        verify_downloads,
+        watch_interval,
+        watch_webhook,
+        watch_state,
         sleep,
+        mode,
         media_links,
     })
 }
@@ -100,7 +260,67 @@ fn main() -> Result<(), Box<dyn Error>> {
     let mut client = YandexMusicClient::new(&settings.token)?;
     println!("Signed in as: {}\n", client.login);
 
+    let mut archive = DownloadArchive::open(settings.download_archive.as_deref())?;
+    let downloads = matches!(settings.mode, Mode::Download | Mode::Sync { .. } | Mode::Watch { .. });
+    if downloads && settings.dedupe.is_enabled() && !archive.is_enabled() {
+        archive::index_library(&mut archive, &settings.out_path)?;
+    }
+
+    match &settings.mode {
+        Mode::Download => {}
+        Mode::Retag { path, dry_run, assume_yes } => {
+            retag::retag_library(&mut client, &settings, path, *dry_run, *assume_yes)?;
+            println!("\nDone!");
+            return Ok(());
+        }
+        Mode::Lyrics { path } => {
+            backfill::backfill_lyrics(&mut client, &settings, path.as_ref())?;
+            println!("\nDone!");
+            return Ok(());
+        }
+        Mode::ImportArchive { path } => {
+            archive::import_library(&mut archive, path)?;
+            println!("\nDone!");
+            return Ok(());
+        }
+        Mode::Sync { delete_removed, likes } => {
+            sync::sync_playlists(&mut client, &settings, &mut archive, *delete_removed, *likes)?;
+            println!("\nDone!");
+            return Ok(());
+        }
+        Mode::Upgrade { path, dry_run, assume_yes, delete_old } => {
+            upgrade::upgrade_library(&mut client, &settings, &mut archive, path, *dry_run, *assume_yes, *delete_old)?;
+            println!("\nDone!");
+            return Ok(());
+        }
+        Mode::Verify { path, redownload, write_manifests } => {
+            verify::verify_library(&mut client, &settings, &mut archive, path, *redownload, *write_manifests)?;
+            println!("\nDone!");
+            return Ok(());
+        }
+        Mode::ReportMissing { path, json } => {
+            report::report_missing(&mut client, &settings, path, json.as_ref())?;
+            println!("\nDone!");
+            return Ok(());
+        }
+        Mode::Watch { subscriptions, once, download_existing } => {
+            let opts = watch::WatchOptions { subscriptions, once: *once, download_existing: *download_existing };
+            watch::watch(&mut client, &settings, &mut archive, &opts)?;
+            println!("\nDone!");
+            return Ok(());
+        }
+        Mode::ListTemplateVars => {
+            for link in &settings.media_links {
+                if let Err(e) = processor::list_template_vars(&mut client, &settings, link) {
+                    eprintln!("Error processing link: {}", e);
+                }
+            }
+            return Ok(());
+        }
+    }
+
     let total_links = settings.media_links.len();
+    let mut batch_entries = Vec::new();
 
     for (i, link) in settings.media_links.iter().enumerate() {
         let current_num = i + 1;
@@ -108,21 +328,22 @@ fn main() -> Result<(), Box<dyn Error>> {
 
         let result = match link {
             MediaLink::Album { album_id } => {
-                processor::process_album(&mut client, &settings, album_id, None, None)
+                processor::process_album(&mut client, &settings, &mut archive, SourceKind::Album, album_id, None, None)
             }
             MediaLink::Track { album_id, track_id } => {
-                processor::process_album(&mut client, &settings, album_id, Some(track_id), None)
+                processor::process_album(&mut client, &settings, &mut archive, SourceKind::Album, album_id, Some(track_id), None)
             }
             MediaLink::Playlist { uuid_or_login } => {
-                processor::process_user_playlist(&mut client, &settings, uuid_or_login)
+                processor::process_user_playlist(&mut client, &settings, &mut archive, uuid_or_login)
             }
             MediaLink::Artist { artist_id } => {
-                processor::process_artist_albums(&mut client, &settings, artist_id)
+                processor::process_artist_albums(&mut client, &settings, &mut archive, artist_id)
             }
         };
 
-        if let Err(e) = result {
-            eprintln!("Error processing link: {}", e);
+        match result {
+            Ok(entries) => batch_entries.extend(entries),
+            Err(e) => eprintln!("Error processing link: {}", e),
         }
 
         if settings.sleep && current_num < total_links {
@@ -131,6 +352,13 @@ fn main() -> Result<(), Box<dyn Error>> {
         }
     }
 
+    if let Some(name) = &settings.batch_playlist {
+        let plist_base = utils::fit_path(&settings.out_path, Path::new(name), ".m3u8".len(), settings.max_path_length);
+        if let Err(e) = playlists::write_playlist_files(&plist_base, name, &batch_entries, true, settings.write_xspf) {
+            eprintln!("Warning: Failed to write batch playlist: {}", e);
+        }
+    }
+
     println!("\nDone!");
     Ok(())
 }
@@ -1,8 +1,17 @@
 use crate::api::structs::{AlbumResult, AlbumResultInPlaylist, Artist, Label, PlaylistTrack, Volume};
-use crate::models::ParsedAlbumMeta;
+use crate::models::{DownloadFormat, ParsedAlbumMeta, SortNameOptions};
+use crate::translit;
 
-pub fn parse_artists(artists: &[Artist]) -> String {
-    artists.iter().map(|a| a.name.clone()).collect::<Vec<String>>().join(", ")
+pub fn parse_artist_names(artists: &[Artist]) -> Vec<String> {
+    artists.iter().map(|a| a.name.clone()).collect()
+}
+
+pub fn parse_artists(artists: &[Artist], separator: &str) -> String {
+    parse_artist_names(artists).join(separator)
+}
+
+pub fn parse_artist_ids(artists: &[Artist]) -> Vec<String> {
+    artists.iter().filter(|a| !a.id.is_empty()).map(|a| a.id.clone()).collect()
 }
 
 pub fn parse_labels(labels: &[Label]) -> String {
@@ -13,63 +22,206 @@ pub fn parse_title(title: &str, version: Option<String>) -> String {
     format!("{}{}", title, version.map_or("".to_string(), |v| format!(" ({})", v)))
 }
 
-pub fn parse_album_meta(meta: &AlbumResult, track_total: u16) -> ParsedAlbumMeta {
+pub fn parse_release_date(date: Option<&String>) -> Option<String> {
+    // "2021-03-19T00:00:00+03:00" -> "2021-03-19"
+    date.and_then(|d| d.get(..10)).map(|d| d.to_string())
+}
+
+pub fn parse_copyright(year: Option<u16>, label: &str) -> Option<String> {
+    if label.is_empty() {
+        return None;
+    }
+    Some(match year {
+        Some(y) => format!("℗ {} {}", y, label),
+        None => format!("℗ {}", label),
+    })
+}
+
+pub fn is_explicit(content_warning: Option<&String>) -> bool {
+    content_warning.is_some_and(|w| w.eq_ignore_ascii_case("explicit"))
+}
+
+pub fn parse_album_meta(meta: &AlbumResult, track_total: u16, artist_sep: &str) -> ParsedAlbumMeta {
     ParsedAlbumMeta {
-        album_artist: parse_artists(&meta.artists),
+        album_id: meta.id.clone(),
+        album_artist: parse_artists(&meta.artists, artist_sep),
+        album_artists: parse_artist_names(&meta.artists),
+        album_artist_ids: parse_artist_ids(&meta.artists),
         album_title: parse_title(&meta.title, meta.version.clone()),
         artist: String::new(),
+        artists: Vec::new(),
+        artist_ids: Vec::new(),
         cover_data: Vec::new(),
+        disc_num: 0,
+        disc_total: meta.volumes.len() as u16,
         genre: meta.genre.clone(),
         lyrics_avail: None,
         is_track_only: false,
         title: String::new(),
+        track_id: String::new(),
         track_num: 0,
         track_total,
         label: parse_labels(&meta.labels),
         timed_lyrics: None,
         untimed_lyrics: None,
         year: meta.year,
+        release_date: parse_release_date(meta.release_date.as_ref()),
+        original_year: meta.original_release_year.filter(|y| Some(*y) != meta.year),
+        album_type: meta.album_type.clone(),
+        copyright: parse_copyright(meta.year, &parse_labels(&meta.labels)),
+        explicit: is_explicit(meta.content_warning.as_ref()),
+        isrc: None,
+        duration_ms: None,
+        bpm: None,
+        original_artist: String::new(),
+        artist_sort: None,
+        album_artist_sort: None,
+        title_sort: None,
+        codec: String::new(),
+        bitrate: 0,
+        quality: String::new(),
+        playlist_name: String::new(),
+        playlist_pos: 0,
     }
 }
 
-pub fn parse_album_meta_playlist(meta: &AlbumResultInPlaylist, track_total: u16) -> ParsedAlbumMeta {
+pub fn parse_album_meta_playlist(meta: &AlbumResultInPlaylist, track_total: u16, artist_sep: &str) -> ParsedAlbumMeta {
     ParsedAlbumMeta {
-        album_artist: parse_artists(&meta.artists),
+        album_id: meta.id.clone(),
+        album_artist: parse_artists(&meta.artists, artist_sep),
+        album_artists: parse_artist_names(&meta.artists),
+        album_artist_ids: parse_artist_ids(&meta.artists),
         album_title: parse_title(&meta.title, meta.version.clone()),
         artist: String::new(),
+        artists: Vec::new(),
+        artist_ids: Vec::new(),
         cover_data: Vec::new(),
+        disc_num: 0,
+        disc_total: 0,
         genre: meta.genre.clone(),
         lyrics_avail: None,
         is_track_only: false,
         title: String::new(),
+        track_id: String::new(),
         track_num: 0,
         track_total,
         timed_lyrics: None,
         untimed_lyrics: None,
         label: parse_labels(&meta.labels),
         year: meta.year,
+        release_date: parse_release_date(meta.release_date.as_ref()),
+        original_year: meta.original_release_year.filter(|y| Some(*y) != meta.year),
+        album_type: meta.album_type.clone(),
+        copyright: parse_copyright(meta.year, &parse_labels(&meta.labels)),
+        explicit: is_explicit(meta.content_warning.as_ref()),
+        isrc: None,
+        duration_ms: None,
+        bpm: None,
+        original_artist: String::new(),
+        artist_sort: None,
+        album_artist_sort: None,
+        title_sort: None,
+        codec: String::new(),
+        bitrate: 0,
+        quality: String::new(),
+        playlist_name: String::new(),
+        playlist_pos: 0,
     }
 }
 
-pub fn parse_track_meta(meta: &mut ParsedAlbumMeta, track_meta: &Volume, track_num: u16, is_track_only: bool) {
-    meta.artist = parse_artists(&track_meta.artists);
+// Returns (disc number, track number, disc track total, track) for a track ID within an album.
+pub fn find_track<'a>(meta: &'a AlbumResult, track_id: &str) -> Option<(u16, u16, u16, &'a Volume)> {
+    meta.volumes.iter().enumerate().find_map(|(disc_idx, volume)| {
+        volume.iter().position(|t| t.id == track_id).map(|track_idx| {
+            (disc_idx as u16 + 1, track_idx as u16 + 1, volume.len() as u16, &volume[track_idx])
+        })
+    })
+}
+
+pub fn parse_track_meta(
+    meta: &mut ParsedAlbumMeta,
+    track_meta: &Volume,
+    disc_num: u16,
+    track_num: u16,
+    track_total: u16,
+    is_track_only: bool,
+    artist_sep: &str,
+) {
+    meta.artist = parse_artists(&track_meta.artists, artist_sep);
+    meta.artists = parse_artist_names(&track_meta.artists);
+    meta.original_artist = meta.artists.first().cloned().unwrap_or_default();
+    meta.artist_ids = parse_artist_ids(&track_meta.artists);
     meta.title = parse_title(&track_meta.title, track_meta.version.clone());
+    meta.track_id = track_meta.id.clone();
+    meta.isrc = track_meta.isrc.clone();
+    meta.duration_ms = track_meta.duration_ms;
+    meta.bpm = track_meta.bpm.map(|b| b.round() as u16).filter(|b| *b > 0);
+    meta.explicit = is_explicit(track_meta.content_warning.as_ref());
+    meta.disc_num = disc_num;
     meta.track_num = track_num;
+    meta.track_total = track_total;
     if let Some(lyrics) = &track_meta.lyrics_info {
         meta.lyrics_avail = lyrics.check_availibility();
     }
     meta.is_track_only = is_track_only;
 }
 
-pub fn parse_track_meta_playlist(meta: &mut ParsedAlbumMeta, track_meta: &PlaylistTrack, track_num: u16) {
-    meta.artist = parse_artists(&track_meta.artists);
+pub fn parse_track_meta_playlist(meta: &mut ParsedAlbumMeta, track_meta: &PlaylistTrack, track_num: u16, artist_sep: &str) {
+    meta.artist = parse_artists(&track_meta.artists, artist_sep);
+    meta.artists = parse_artist_names(&track_meta.artists);
+    meta.original_artist = meta.artists.first().cloned().unwrap_or_default();
+    meta.artist_ids = parse_artist_ids(&track_meta.artists);
     meta.title = parse_title(&track_meta.title, track_meta.version.clone());
+    meta.track_id = track_meta.id.clone();
+    meta.isrc = track_meta.isrc.clone();
+    meta.duration_ms = track_meta.duration_ms;
+    meta.bpm = track_meta.bpm.map(|b| b.round() as u16).filter(|b| *b > 0);
+    meta.explicit = is_explicit(track_meta.content_warning.as_ref());
     meta.track_num = track_num;
     if let Some(lyrics) = &track_meta.lyrics_info {
         meta.lyrics_avail = lyrics.check_availibility();
     }
 }
 
+// "Кино" -> "Kino", "The Beatles" -> "Beatles, The". Overrides win over both.
+fn parse_sort_name(name: &str, opts: &SortNameOptions) -> String {
+    if let Some(sort_name) = opts.overrides.get(name) {
+        return sort_name.clone();
+    }
+    let latin = translit::transliterate(name, opts.translit_scheme);
+    for article in &opts.articles {
+        let Some(head) = latin.get(..article.len()) else {
+            continue;
+        };
+        let rest = latin[article.len()..].strip_prefix(' ').unwrap_or_default().trim_start();
+        if head.eq_ignore_ascii_case(article) && !rest.is_empty() {
+            return format!("{}, {}", rest, head);
+        }
+    }
+    latin
+}
+
+// Each artist is sorted on its own, unless the joined credit has an override.
+fn parse_sort_artists(joined: &str, names: &[String], opts: &SortNameOptions, artist_sep: &str) -> String {
+    if let Some(sort_name) = opts.overrides.get(joined) {
+        return sort_name.clone();
+    }
+    names.iter()
+        .map(|n| parse_sort_name(n, opts))
+        .collect::<Vec<String>>()
+        .join(artist_sep)
+}
+
+pub fn parse_sort_names(meta: &mut ParsedAlbumMeta, opts: &SortNameOptions, artist_sep: &str) {
+    meta.artist_sort = Some(parse_sort_artists(&meta.artist, &meta.artists, opts, artist_sep));
+    meta.album_artist_sort = Some(parse_sort_artists(&meta.album_artist, &meta.album_artists, opts, artist_sep));
+    meta.title_sort = Some(parse_sort_name(&meta.title, opts));
+}
+
+pub fn parse_source_url(album_id: &str, track_id: &str) -> String {
+    format!("https://music.yandex.ru/album/{}/track/{}", album_id, track_id)
+}
+
 pub fn parse_specs(codec: &str, bitrate: u16) -> Option<(String, String)> {
     match codec {
         "flac-mp4" => Some(("FLAC".to_string(), "flac".to_string())),
@@ -77,4 +229,37 @@ pub fn parse_specs(codec: &str, bitrate: u16) -> Option<(String, String)> {
         "aac-mp4" | "he-aac-mp4" => Some((format!("{} Kbps AAC", bitrate), "m4a".to_string())),
         _ => None,
     }
-}
\ No newline at end of file
+}
+
+// Which download format a codec and bitrate (in Kbps) correspond to.
+pub fn parse_quality(codec: &str, bitrate: u32) -> Option<DownloadFormat> {
+    match codec {
+        "flac-mp4" => Some(DownloadFormat::Flac),
+        "mp3" | "mp3-mp4" => Some(DownloadFormat::Hq),
+        "aac-mp4" | "he-aac-mp4" => Some(match bitrate {
+            0..=96 => DownloadFormat::Aac64,
+            97..=224 => DownloadFormat::Aac192,
+            _ => DownloadFormat::Hq,
+        }),
+        _ => None,
+    }
+}
+
+pub fn parse_codec_name(codec: &str) -> Option<&'static str> {
+    match codec {
+        "flac" | "flac-mp4" => Some("FLAC"),
+        "mp3" | "mp3-mp4" => Some("MP3"),
+        "aac" | "aac-mp4" => Some("AAC"),
+        "he-aac" | "he-aac-mp4" => Some("HE-AAC"),
+        _ => None,
+    }
+}
+
+pub fn parse_codec_from_ext(ext: &str) -> Option<&'static str> {
+    match ext.to_lowercase().as_str() {
+        "flac" => Some("flac-mp4"),
+        "mp3" => Some("mp3"),
+        "m4a" => Some("aac-mp4"),
+        _ => None,
+    }
+}
@@ -1,6 +1,8 @@
+use std::collections::HashMap;
 use std::path::PathBuf;
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+// Ordered from worst to best.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
 pub enum DownloadFormat {
     Aac64,  // 1 -> "lq"
     Aac192, // 2 -> "nq"
@@ -19,6 +21,16 @@ impl DownloadFormat {
         }
     }
 
+    pub fn from_api_str(val: &str) -> Option<Self> {
+        match val {
+            "lq" => Some(Self::Aac64),
+            "nq" => Some(Self::Aac192),
+            "hq" => Some(Self::Hq),
+            "lossless" => Some(Self::Flac),
+            _ => None,
+        }
+    }
+
     pub fn as_api_str(&self) -> &'static str {
         match self {
             Self::Aac64 => "lq",
@@ -29,6 +41,124 @@ impl DownloadFormat {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlaylistLayout {
+    Flat,   // "flat"   -> all tracks in one playlist folder, numbered by position
+    Albums, // "albums" -> tracks go to their album folders, plus a playlist file
+}
+
+impl PlaylistLayout {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "flat" => Some(Self::Flat),
+            "albums" => Some(Self::Albums),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TranslitScheme {
+    Gost, // "gost" -> GOST 7.79 system B (ISO 9 in ASCII): Щука -> Shhuka
+    Bgn,  // "bgn"  -> BGN/PCGN style: Щука -> Shchuka
+}
+
+impl TranslitScheme {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "gost" | "iso9" => Some(Self::Gost),
+            "bgn" => Some(Self::Bgn),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilenameCharset {
+    Unicode,  // "unicode"  -> kept as is, NFC-normalised
+    Translit, // "translit" -> Cyrillic transliterated to Latin, other scripts kept
+    Ascii,    // "ascii"    -> everything converted to ASCII
+}
+
+impl FilenameCharset {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "unicode" => Some(Self::Unicode),
+            "translit" => Some(Self::Translit),
+            "ascii" => Some(Self::Ascii),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkMode {
+    Off,      // "off"      -> downloaded again
+    Hardlink, // "hardlink" -> hard link to the existing file, copied across drives
+    Symlink,  // "symlink"  -> relative symlink, copied where symlinks aren't allowed
+    Copy,     // "copy"     -> plain copy
+}
+
+impl LinkMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "off" | "" => Some(Self::Off),
+            "hardlink" => Some(Self::Hardlink),
+            "symlink" => Some(Self::Symlink),
+            "copy" => Some(Self::Copy),
+            _ => None,
+        }
+    }
+}
+
+// What kind of link a track is being downloaded for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SourceKind {
+    Album,
+    Artist,
+    Playlist,
+}
+
+// How tracks already in the library are reused, per source kind.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DedupePolicy {
+    pub album: LinkMode,
+    pub artist: LinkMode,
+    pub playlist: LinkMode,
+}
+
+impl DedupePolicy {
+    pub fn mode(&self, source: SourceKind) -> LinkMode {
+        match source {
+            SourceKind::Album => self.album,
+            SourceKind::Artist => self.artist,
+            SourceKind::Playlist => self.playlist,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        [self.album, self.artist, self.playlist].iter().any(|mode| *mode != LinkMode::Off)
+    }
+}
+
+// Sort names are generated when this is set.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SortNameOptions {
+    pub translit_scheme: TranslitScheme,
+    pub articles: Vec<String>,
+    pub overrides: HashMap<String, String>,
+}
+
+// How template output is turned into file and folder names. Tags are unaffected.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilenamePolicy {
+    pub charset: FilenameCharset,
+    pub translit_scheme: TranslitScheme,
+    pub replacement: String,
+    pub collapse_whitespace: bool,
+    pub trim: bool,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum MediaLink {
     Album { album_id: String },
@@ -37,41 +167,105 @@ pub enum MediaLink {
     Artist { artist_id: String },
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mode {
+    Download,
+    Retag { path: PathBuf, dry_run: bool, assume_yes: bool },
+    Lyrics { path: Option<PathBuf> },
+    ImportArchive { path: PathBuf },
+    Sync { delete_removed: bool, likes: bool },
+    Watch { subscriptions: PathBuf, once: bool, download_existing: bool },
+    Upgrade { path: PathBuf, dry_run: bool, assume_yes: bool, delete_old: bool },
+    ReportMissing { path: PathBuf, json: Option<PathBuf> },
+    Verify { path: PathBuf, redownload: bool, write_manifests: bool },
+    ListTemplateVars,
+}
+
 #[derive(Debug, Clone)]
 pub struct Settings {
     pub token: String,
 
     pub format: DownloadFormat,
     pub out_path: PathBuf,
+    pub download_archive: Option<PathBuf>,
     pub ffmpeg_path: PathBuf,
 
     pub keep_covers: bool,
     pub write_covers: bool,
     pub get_original_covers: bool,
+    pub cover_max_size: Option<u32>,
+    pub cover_max_kb: Option<u32>,
     pub write_lyrics: bool,
+    pub embed_lyrics: bool,
+    pub lrc_headers: bool,
+    pub plain_lyrics: bool,
+    pub disc_folders: bool,
+    pub write_artist_nfo: bool,
+    pub write_album_sidecars: bool,
 
     pub album_template: String,
     pub track_template: String,
+    pub artist_separator: String,
+    pub playlist_template: String,
+    pub playlist_layout: PlaylistLayout,
+    pub write_m3u8: bool,
+    pub write_xspf: bool,
+    pub batch_playlist: Option<String>,
+    pub max_path_length: usize,
+    pub filename_policy: FilenamePolicy,
+    pub sort_names: Option<SortNameOptions>,
+    pub dedupe: DedupePolicy,
+    pub verify_downloads: bool,
+
+    pub watch_interval: u64,
+    pub watch_webhook: Option<String>,
+    pub watch_state: Option<PathBuf>,
 
     pub sleep: bool,
 
+    pub mode: Mode,
     pub media_links: Vec<MediaLink>,
 }
 
 #[derive(Debug, Clone)]
 pub struct ParsedAlbumMeta {
+    pub album_id: String,
     pub album_title: String,
     pub album_artist: String,
+    pub album_artists: Vec<String>,
+    pub album_artist_ids: Vec<String>,
     pub artist: String,
+    pub artists: Vec<String>,
+    pub artist_ids: Vec<String>,
     pub cover_data: Vec<u8>,
+    pub disc_num: u16,
+    pub disc_total: u16,
     pub genre: Option<String>,
     pub lyrics_avail: Option<bool>,
     pub is_track_only: bool,
     pub label: String,
     pub title: String,
+    pub track_id: String,
     pub timed_lyrics: Option<String>,
     pub untimed_lyrics: Option<String>,
     pub track_num: u16,
     pub track_total: u16,
     pub year: Option<u16>,
+    pub release_date: Option<String>,
+    pub original_year: Option<u16>,
+    pub album_type: Option<String>,
+    pub copyright: Option<String>,
+    pub explicit: bool,
+    pub isrc: Option<String>,
+    pub duration_ms: Option<u64>,
+    pub bpm: Option<u16>,
+    pub original_artist: String,
+    pub artist_sort: Option<String>,
+    pub album_artist_sort: Option<String>,
+    pub title_sort: Option<String>,
+    pub codec: String,
+    pub bitrate: u16,
+    pub quality: String,
+    pub playlist_name: String,
+    pub playlist_pos: u16,
 }
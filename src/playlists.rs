@@ -0,0 +1,136 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::models::ParsedAlbumMeta;
+use crate::sidecars::xml_escape;
+use crate::utils;
+
+pub enum PlaylistEntry {
+    Track { path: PathBuf, artist: String, title: String, duration_ms: Option<u64> },
+    Skipped { artist: String, title: String, reason: String },
+}
+
+impl PlaylistEntry {
+    pub fn track(path: PathBuf, meta: &ParsedAlbumMeta) -> Self {
+        Self::Track {
+            path,
+            artist: meta.artist.clone(),
+            title: meta.title.clone(),
+            duration_ms: meta.duration_ms,
+        }
+    }
+
+    pub fn skipped(artist: String, title: String, reason: impl ToString) -> Self {
+        Self::Skipped { artist, title, reason: reason.to_string() }
+    }
+
+    fn describe(artist: &str, title: &str) -> String {
+        if artist.is_empty() {
+            title.to_string()
+        } else {
+            format!("{} - {}", artist, title)
+        }
+    }
+}
+
+// Paths in playlist files are relative to the playlist, with / separators.
+fn entry_location(base_dir: &Path, path: &Path) -> String {
+    let rel = utils::relative_path(base_dir, path);
+    rel.iter()
+        .map(|c| c.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn uri_encode(path: &str) -> String {
+    let mut out = String::new();
+    for b in path.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => out.push(b as char),
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+pub fn write_m3u8(path: &Path, title: &str, entries: &[PlaylistEntry]) -> Result<(), Box<dyn Error>> {
+    let base_dir = path.parent().unwrap_or(Path::new(""));
+    let mut out = String::from("#EXTM3U\n");
+    out.push_str(&format!("#PLAYLIST:{}\n", title));
+
+    for entry in entries {
+        match entry {
+            PlaylistEntry::Track { path, artist, title, duration_ms } => {
+                let secs = duration_ms.map(|ms| (ms / 1000) as i64).unwrap_or(-1);
+                out.push_str(&format!("#EXTINF:{},{}\n", secs, PlaylistEntry::describe(artist, title)));
+                out.push_str(&entry_location(base_dir, path));
+                out.push('\n');
+            }
+            PlaylistEntry::Skipped { artist, title, reason } => {
+                out.push_str(&format!("# Skipped: {} ({})\n", PlaylistEntry::describe(artist, title), reason));
+            }
+        }
+    }
+    fs::write(path, out)?;
+    Ok(())
+}
+
+pub fn write_xspf(path: &Path, title: &str, entries: &[PlaylistEntry]) -> Result<(), Box<dyn Error>> {
+    let base_dir = path.parent().unwrap_or(Path::new(""));
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    out.push_str("<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n");
+    out.push_str(&format!("  <title>{}</title>\n", xml_escape(title)));
+    out.push_str("  <trackList>\n");
+
+    for entry in entries {
+        match entry {
+            PlaylistEntry::Track { path, artist, title, duration_ms } => {
+                out.push_str("    <track>\n");
+                out.push_str(&format!("      <location>{}</location>\n", uri_encode(&entry_location(base_dir, path))));
+                out.push_str(&format!("      <creator>{}</creator>\n", xml_escape(artist)));
+                out.push_str(&format!("      <title>{}</title>\n", xml_escape(title)));
+                if let Some(ms) = duration_ms {
+                    out.push_str(&format!("      <duration>{}</duration>\n", ms));
+                }
+                out.push_str("    </track>\n");
+            }
+            PlaylistEntry::Skipped { artist, title, reason } => {
+                // "--" isn't allowed inside XML comments.
+                let text = format!("Skipped: {} ({})", PlaylistEntry::describe(artist, title), reason);
+                out.push_str(&format!("    <!-- {} -->\n", xml_escape(&text).replace("--", "- -")));
+            }
+        }
+    }
+
+    out.push_str("  </trackList>\n</playlist>\n");
+    fs::write(path, out)?;
+    Ok(())
+}
+
+// Writes <base>.m3u8 and/or <base>.xspf. Nothing is written if no track was saved.
+pub fn write_playlist_files(
+    base: &Path,
+    title: &str,
+    entries: &[PlaylistEntry],
+    m3u8: bool,
+    xspf: bool,
+) -> Result<(), Box<dyn Error>> {
+    if !entries.iter().any(|e| matches!(e, PlaylistEntry::Track { .. })) {
+        return Ok(());
+    }
+    if let Some(parent) = base.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if m3u8 {
+        let path = utils::add_extension(base, "m3u8");
+        write_m3u8(&path, title, entries)?;
+        println!("Playlist saved to: {:?}", path);
+    }
+    if xspf {
+        let path = utils::add_extension(base, "xspf");
+        write_xspf(&path, title, entries)?;
+        println!("Playlist saved to: {:?}", path);
+    }
+    Ok(())
+}
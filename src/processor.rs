@@ -1,7 +1,8 @@
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fs;
 use std::io::Read;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 use std::thread;
 use std::time::Duration;
@@ -10,19 +11,34 @@ use indicatif::{ProgressBar, ProgressStyle};
 use reqwest::Error as ReqwestErr;
 
 use crate::api::client::YandexMusicClient;
-use crate::models::{ParsedAlbumMeta, Settings};
+use crate::archive::DownloadArchive;
+use crate::api::structs::{AlbumResult, ArtistMetaArtist, DownloadInfo, PlaylistMetaResult, PlaylistTrack};
+use crate::models::{DownloadFormat, LinkMode, MediaLink, ParsedAlbumMeta, PlaylistLayout, Settings, SourceKind};
+use crate::covers;
+use crate::dedupe;
+use crate::lyrics;
 use crate::utils;
 use crate::metadata;
+use crate::playlists::{self, PlaylistEntry};
+use crate::sidecars;
 use crate::tags;
+use crate::verify;
+use crate::template;
 
 const BUF_SIZE: usize = 1024 * 1024;
+const PLAYLIST_EXT_RESERVE: usize = ".m3u8".len();
+const VERIFY_ATTEMPTS: u32 = 3;
 
-pub fn process_artist_albums(c: &mut YandexMusicClient, settings: &Settings, artist_id: &str) -> Result<(), Box<dyn Error>> {
+pub fn process_artist_albums(
+    c: &mut YandexMusicClient,
+    settings: &Settings,
+    archive: &mut DownloadArchive,
+    artist_id: &str,
+) -> Result<Vec<PlaylistEntry>, Box<dyn Error>> {
     let meta = c.get_artist_meta(artist_id)?;
     println!("Artist: {}", meta.artist.name);
 
-    let sanitized_artist_name = utils::sanitise(&meta.artist.name);
-    let artist_path = settings.out_path.join(&sanitized_artist_name);
+    let artist_path = artist_path(settings, &meta.artist.name);
 
     let album_ids = meta.albums;
     let album_total = album_ids.len();
@@ -31,85 +47,296 @@ pub fn process_artist_albums(c: &mut YandexMusicClient, settings: &Settings, art
         return Err("Artist has no albums".into());
     }
 
+    fs::create_dir_all(&artist_path)?;
+
+    if settings.keep_covers {
+        if let Err(e) = write_artist_cover(c, settings, &meta.artist, &artist_path) {
+            eprintln!("Warning: Failed to save artist cover: {}", e);
+        }
+    }
+
+    if settings.write_artist_nfo {
+        let nfo_path = artist_path.join("artist.nfo");
+        if let Err(e) = sidecars::write_artist_nfo(&nfo_path, &meta.artist) {
+            eprintln!("Warning: Failed to write artist NFO {:?}: {}", nfo_path, e);
+        }
+    }
+
+    let mut entries = Vec::new();
+
     for (i, album) in album_ids.iter().enumerate() {
         let current_album = i + 1;
         println!("\nAlbum {} of {}:", current_album, album_total);
 
-        if let Err(e) = process_album(c, settings, &album.id.to_string(), None, Some(&artist_path)) {
-            eprintln!("Failed to process album ID {}: {}", album.id, e);
+        match process_album(c, settings, archive, SourceKind::Artist, &album.id.to_string(), None, Some(&artist_path)) {
+            Ok(album_entries) => entries.extend(album_entries),
+            Err(e) => {
+                eprintln!("Failed to process album ID {}: {}", album.id, e);
+                entries.push(PlaylistEntry::skipped(meta.artist.name.clone(), format!("album {}", album.id), e));
+            }
         }
 
         if settings.sleep && current_album < album_total {
             thread::sleep(Duration::from_secs(1));
         }
     }
-    Ok(())
-}
 
-pub fn process_user_playlist(c: &mut YandexMusicClient, settings: &Settings, login: &str) -> Result<(), Box<dyn Error>> {
-    let playlist = c.get_other_user_playlist_meta(login)?;
-    if playlist.visibility.to_lowercase() != "public" {
-        return Err("Playlist is private".into());
+    let plist_base = playlist_base(&artist_path, settings);
+    if let Err(e) = playlists::write_playlist_files(&plist_base, &meta.artist.name, &entries, settings.write_m3u8, settings.write_xspf) {
+        eprintln!("Warning: Failed to write artist playlist: {}", e);
     }
+    Ok(entries)
+}
 
-    let meta = c.get_playlist_meta(&playlist.playlist_uuid)?;
-    if !meta.available {
-        return Err("Playlist is unavailable".into());
+pub(crate) fn artist_path(settings: &Settings, artist_name: &str) -> PathBuf {
+    let sanitized_artist_name = utils::sanitise(artist_name, &settings.filename_policy);
+    utils::fit_path(&settings.out_path, Path::new(&sanitized_artist_name), utils::DIR_RESERVE, settings.max_path_length)
+}
+
+// Saved as both artist.* and folder.* since media servers disagree on the name.
+fn write_artist_cover(
+    c: &mut YandexMusicClient,
+    settings: &Settings,
+    artist: &ArtistMetaArtist,
+    artist_path: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let uri = artist.cover.as_ref()
+        .and_then(|cover| cover.uri.as_ref())
+        .or(artist.og_image.as_ref());
+    let Some(uri) = uri else {
+        return Ok(());
+    };
+
+    let cover_data = get_cover_data(c, uri, settings.get_original_covers)?;
+    let cover_ext = covers::detect_type(&cover_data).extension();
+    for name in ["artist", "folder"] {
+        let cover_path = artist_path.join(name).with_extension(cover_ext);
+        fs::write(&cover_path, &cover_data)?;
+        println!("Artist cover saved to: {:?}", &cover_path);
     }
+    Ok(())
+}
 
-    let plist_folder = format!("{} - {}", meta.owner.login, meta.title);
-    println!("Playlist: {}", plist_folder);
+pub fn process_user_playlist(
+    c: &mut YandexMusicClient,
+    settings: &Settings,
+    archive: &mut DownloadArchive,
+    login: &str,
+) -> Result<Vec<PlaylistEntry>, Box<dyn Error>> {
+    let meta = get_public_playlist(c, login)?;
+    process_playlist_tracks(c, settings, archive, meta, None)
+}
 
-    let san_album_folder = utils::sanitise(&plist_folder);
-    let plist_path = settings.out_path.join(san_album_folder);
-    fs::create_dir_all(&plist_path)?;
+// With `only`, just those tracks are downloaded and no playlist file is written,
+// since it would be missing the rest. Returns one entry per track handled, in playlist order.
+pub(crate) fn process_playlist_tracks(
+    c: &mut YandexMusicClient,
+    settings: &Settings,
+    archive: &mut DownloadArchive,
+    meta: PlaylistMetaResult,
+    only: Option<&HashSet<String>>,
+) -> Result<Vec<PlaylistEntry>, Box<dyn Error>> {
+    println!("Playlist: {} - {}", meta.owner.login, meta.title);
+    let plist_path = playlist_path(settings, &meta);
 
     let track_total = meta.tracks.len() as u16;
+    let mut entries = Vec::new();
+    let mut albums: HashMap<String, PreparedAlbum> = HashMap::new();
 
     for (mut track_num, t) in meta.tracks.into_iter().enumerate() {
         let track = t.track;
+        if only.is_some_and(|ids| !ids.contains(&track.id)) {
+            continue;
+        }
+        let track_artist = metadata::parse_artists(&track.artists, &settings.artist_separator);
+        let track_title = metadata::parse_title(&track.title, track.version.clone());
+
         if track.track_source.to_lowercase() != "own" {
             println!("Skipped user-uploaded track.");
+            entries.push(PlaylistEntry::skipped(track_artist, track_title, "user-uploaded"));
             continue;
         }
 
         track_num += 1;
 
-        if !track.available || !track.albums[0].available {
+        if !track.available || track.albums.is_empty() || !track.albums[0].available {
             println!("Track or Album is unavailable.");
+            entries.push(PlaylistEntry::skipped(track_artist, track_title, "unavailable"));
+            continue;
+        }
+
+        // Filed like a single track download, keeping the real album numbering.
+        // Each album is only fetched once per playlist.
+        if settings.playlist_layout == PlaylistLayout::Albums {
+            let album_id = track.albums[0].id.clone();
+            let result = match albums.get(&album_id) {
+                Some(album) => Ok(album),
+                None => prepare_album(c, settings, &album_id, Some(&track.id), None)
+                    .map(|album| &*albums.entry(album_id).or_insert(album)),
+            };
+            let result = result.and_then(|album| {
+                let playlist = Some((meta.title.as_str(), track_num as u16));
+                process_album_tracks(c, settings, archive, SourceKind::Playlist, album, Some(&track.id), playlist)
+            });
+            match result {
+                Ok(album_entries) => entries.extend(album_entries),
+                Err(e) => {
+                    eprintln!("Track failed: {}", e);
+                    entries.push(PlaylistEntry::skipped(track_artist, track_title, e));
+                }
+            }
             continue;
         }
 
-        let mut parsed_meta = metadata::parse_album_meta_playlist(&track.albums[0], track_total);
-        if let Some(uri) = &track.cover_uri {
-            parsed_meta.cover_data = get_cover_data(c, uri, settings.get_original_covers)?;
+        let mut parsed_meta = playlist_track_meta(settings, &meta.title, &track, track_num as u16, track_total);
+        if let Some(uri) = track.cover_uri.as_ref().filter(|_| settings.write_covers) {
+            match get_cover_data(c, uri, settings.get_original_covers) {
+                Ok(cover_data) => parsed_meta.cover_data = prepare_embedded_cover(cover_data, settings),
+                Err(e) => eprintln!("Warning: Failed to download cover for {}: {}", track_title, e),
+            }
         }
 
-        metadata::parse_track_meta_playlist(&mut parsed_meta, &track, track_num as u16);
-        if let Err(e) = process_track(c, &track.id, &mut parsed_meta, settings, &plist_path) {
-            eprintln!("Track failed: {:?}", e);
+        match process_track(c, archive, SourceKind::Playlist, &track.id, &mut parsed_meta, settings, &plist_path) {
+            Ok(path) => entries.push(PlaylistEntry::track(path, &parsed_meta)),
+            Err(e) => {
+                eprintln!("Track failed: {:?}", e);
+                entries.push(PlaylistEntry::skipped(track_artist, track_title, e));
+            }
         }
     }
-    Ok(())
+
+    if only.is_some() {
+        return Ok(entries);
+    }
+
+    // With the albums layout the playlist file is the only thing tying the tracks
+    // together, so it's always written. Flat playlists keep it inside their folder.
+    let plist_title = format!("{} - {}", meta.owner.login, meta.title);
+    let result = match settings.playlist_layout {
+        PlaylistLayout::Albums => {
+            playlists::write_playlist_files(&plist_path, &plist_title, &entries, true, settings.write_xspf)
+        }
+        PlaylistLayout::Flat => {
+            let plist_base = playlist_base(&plist_path, settings);
+            playlists::write_playlist_files(&plist_base, &plist_title, &entries, settings.write_m3u8, settings.write_xspf)
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("Warning: Failed to write playlist file: {}", e);
+    }
+    Ok(entries)
+}
+
+pub(crate) fn get_public_playlist(c: &mut YandexMusicClient, login: &str) -> Result<PlaylistMetaResult, Box<dyn Error>> {
+    let playlist = c.get_other_user_playlist_meta(login)?;
+    if playlist.visibility.to_lowercase() != "public" {
+        return Err("Playlist is private".into());
+    }
+
+    let meta = c.get_playlist_meta(&playlist.playlist_uuid)?;
+    if !meta.available {
+        return Err("Playlist is unavailable".into());
+    }
+    Ok(meta)
+}
+
+// Folder of a flat playlist, or the playlist file name without extension.
+pub(crate) fn playlist_path(settings: &Settings, meta: &PlaylistMetaResult) -> PathBuf {
+    let plist_name = utils::parse_playlist_template(
+        &settings.playlist_template,
+        &meta.owner.login,
+        &meta.title,
+        &settings.filename_policy,
+    );
+    utils::fit_path(&settings.out_path, &plist_name, utils::DIR_RESERVE, settings.max_path_length)
+}
+
+// Flat playlist tracks are numbered by their playlist position.
+pub(crate) fn playlist_track_meta(
+    settings: &Settings,
+    playlist_name: &str,
+    track: &PlaylistTrack,
+    track_num: u16,
+    track_total: u16,
+) -> ParsedAlbumMeta {
+    let mut meta = metadata::parse_album_meta_playlist(&track.albums[0], track_total, &settings.artist_separator);
+    metadata::parse_track_meta_playlist(&mut meta, track, track_num, &settings.artist_separator);
+    meta.playlist_name = playlist_name.to_string();
+    meta.playlist_pos = track_num;
+    meta
 }
 
 pub fn process_album(
     c: &mut YandexMusicClient,
     settings: &Settings,
+    archive: &mut DownloadArchive,
+    source: SourceKind,
     album_id: &str,
     single_track_id: Option<&String>,
     artist_path: Option<&PathBuf>,
-) -> Result<(), Box<dyn Error>> {
-    let mut album_meta = c.get_album_meta(album_id)?;
+) -> Result<Vec<PlaylistEntry>, Box<dyn Error>> {
+    let album = prepare_album(c, settings, album_id, single_track_id, artist_path)?;
+    let entries = process_album_tracks(c, settings, archive, source, &album, single_track_id, None)?;
+
+    if single_track_id.is_none() {
+        let plist_base = playlist_base(&album.path, settings);
+        let plist_title = format!("{} - {}", album.meta.album_artist, album.meta.album_title);
+        if let Err(e) = playlists::write_playlist_files(&plist_base, &plist_title, &entries, settings.write_m3u8, settings.write_xspf) {
+            eprintln!("Warning: Failed to write album playlist: {}", e);
+        }
+    }
+    Ok(entries)
+}
+
+// An album's metadata, folder and cover, shared by every track downloaded from it.
+struct PreparedAlbum {
+    album: AlbumResult,
+    meta: ParsedAlbumMeta,
+    path: PathBuf,
+}
+
+fn check_track_in_album(album: &AlbumResult, single_track_id: Option<&String>) -> Result<(), Box<dyn Error>> {
+    if let Some(tid) = single_track_id {
+        if !album.volumes.iter().flatten().any(|track| &track.id == tid) {
+            return Err("Track not found in this album".into());
+        }
+    }
+    Ok(())
+}
+
+fn prepare_album(
+    c: &mut YandexMusicClient,
+    settings: &Settings,
+    album_id: &str,
+    single_track_id: Option<&String>,
+    artist_path: Option<&PathBuf>,
+) -> Result<PreparedAlbum, Box<dyn Error>> {
+    let album_meta = c.get_album_meta(album_id)?;
     if !album_meta.available {
         return Err("Album is unavailable".into());
     }
 
     let track_total: usize = album_meta.volumes.iter().map(|v| v.len()).sum();
-    let mut parsed_meta = metadata::parse_album_meta(&album_meta, track_total as u16);
+    let mut parsed_meta = metadata::parse_album_meta(&album_meta, track_total as u16, &settings.artist_separator);
+
+    // Codec vars in the folder name come from the first track that will be downloaded.
+    if template::uses_any(&settings.album_template, utils::FILE_INFO_TEMPLATE_VARS) {
+        let first_track = album_meta.volumes.iter()
+            .flatten()
+            .find(|t| t.available && single_track_id.is_none_or(|tid| &t.id == tid));
+        if let Some(track) = first_track {
+            let info = c.get_file_info(&track.id, settings.format.as_api_str())?;
+            parse_file_info(&mut parsed_meta, &info)?;
+        }
+    }
 
-    let album_folder_name = utils::parse_album_template(&settings.album_template, &parsed_meta);
-    let album_path = artist_path.unwrap_or(&settings.out_path).join(album_folder_name);
+    let album_folder_name = utils::parse_album_template(&settings.album_template, &parsed_meta, &settings.filename_policy);
+    let album_path = utils::fit_path(
+        artist_path.unwrap_or(&settings.out_path),
+        &album_folder_name,
+        utils::DIR_RESERVE,
+        settings.max_path_length,
+    );
 
     fs::create_dir_all(&album_path)?;
     println!("Album: {} - {}", parsed_meta.album_artist, parsed_meta.album_title);
@@ -118,86 +345,302 @@ pub fn process_album(
         if settings.keep_covers || settings.write_covers {
             let cover_data = get_cover_data(c, uri, settings.get_original_covers)?;
             if settings.keep_covers {
-                let cover_path = album_path.join("folder.jpg");
+                let cover_ext = covers::detect_type(&cover_data).extension();
+                let cover_path = album_path.join("folder").with_extension(cover_ext);
                 if let Err(e) = fs::write(&cover_path, &cover_data) {
-                    eprintln!("Warning: Failed to write cover.jpg file {:?}: {}", &cover_path, e);
+                    eprintln!("Warning: Failed to write cover file {:?}: {}", &cover_path, e);
                 } else {
-                    println!("Lyrics saved to: {:?}", &cover_path);
+                    println!("Cover saved to: {:?}", &cover_path);
                 }
             }
             if settings.write_covers {
-                parsed_meta.cover_data = cover_data;
+                parsed_meta.cover_data = prepare_embedded_cover(cover_data, settings);
             }
         }
     }
 
-    if let Some(tid) = single_track_id {
-        for volume in &mut album_meta.volumes {
-            volume.retain(|track| &track.id == tid);
-        }
-        if album_meta.volumes.iter().all(|v| v.is_empty()) {
-            return Err("Track not found in this album".into());
-        }
+    check_track_in_album(&album_meta, single_track_id)?;
+
+    if settings.write_album_sidecars {
+        write_album_sidecars(&album_path, &parsed_meta, &album_meta);
     }
 
-    let mut global_track_num = 0;
-    for volume in album_meta.volumes {
-        for track in volume {
-            global_track_num += 1;
+    Ok(PreparedAlbum { album: album_meta, meta: parsed_meta, path: album_path })
+}
+
+// `playlist` holds the name and position of the playlist a single track was taken from.
+fn process_album_tracks(
+    c: &mut YandexMusicClient,
+    settings: &Settings,
+    archive: &mut DownloadArchive,
+    source: SourceKind,
+    album: &PreparedAlbum,
+    single_track_id: Option<&String>,
+    playlist: Option<(&str, u16)>,
+) -> Result<Vec<PlaylistEntry>, Box<dyn Error>> {
+    check_track_in_album(&album.album, single_track_id)?;
+
+    let use_disc_folders = settings.disc_folders && album.meta.disc_total > 1;
+    let mut entries = Vec::new();
+
+    for (disc_idx, volume) in album.album.volumes.iter().enumerate() {
+        let disc_num = disc_idx as u16 + 1;
+        let disc_track_total = volume.len() as u16;
+
+        let disc_path = if use_disc_folders {
+            album.path.join(format!("Disc {}", disc_num))
+        } else {
+            album.path.clone()
+        };
+
+        for (track_idx, track) in volume.iter().enumerate() {
+            if let Some(tid) = single_track_id {
+                if &track.id != tid {
+                    continue;
+                }
+            }
+
             if !track.available {
                 println!("Track is unavailable.");
+                entries.push(PlaylistEntry::skipped(
+                    metadata::parse_artists(&track.artists, &settings.artist_separator),
+                    metadata::parse_title(&track.title, track.version.clone()),
+                    "unavailable",
+                ));
                 continue;
             }
 
-            let mut track_meta = parsed_meta.clone();
-            metadata::parse_track_meta(&mut track_meta, &track, global_track_num as u16, single_track_id.is_some());
+            fs::create_dir_all(&disc_path)?;
+
+            let mut track_meta = album.meta.clone();
+            metadata::parse_track_meta(
+                &mut track_meta,
+                track,
+                disc_num,
+                track_idx as u16 + 1,
+                disc_track_total,
+                single_track_id.is_some() && playlist.is_none(),
+                &settings.artist_separator,
+            );
+            if let Some((name, pos)) = playlist {
+                track_meta.playlist_name = name.to_string();
+                track_meta.playlist_pos = pos;
+            }
 
-            if let Err(e) = process_track(c, &track.id, &mut track_meta, settings, &album_path) {
-                eprintln!("Failed to download track {}: {}", track.title, e);
+            match process_track(c, archive, source, &track.id, &mut track_meta, settings, &disc_path) {
+                Ok(path) => entries.push(PlaylistEntry::track(path, &track_meta)),
+                Err(e) => {
+                    eprintln!("Failed to download track {}: {}", track.title, e);
+                    entries.push(PlaylistEntry::skipped(track_meta.artist.clone(), track_meta.title.clone(), e));
+                }
             }
         }
     }
-    Ok(())
+
+    if settings.verify_downloads {
+        write_manifests(&entries);
+    }
+    Ok(entries)
 }
 
-fn process_track(
+// One checksum manifest per folder tracks were saved to.
+fn write_manifests(entries: &[PlaylistEntry]) {
+    let mut dirs: Vec<&Path> = entries.iter()
+        .filter_map(|e| match e {
+            PlaylistEntry::Track { path, .. } if path.is_file() => path.parent(),
+            _ => None,
+        })
+        .collect();
+    dirs.dedup();
+    for dir in dirs {
+        if let Err(e) = verify::write_manifest(dir) {
+            eprintln!("Warning: Failed to write checksum manifest in {:?}: {}", dir, e);
+        }
+    }
+}
+
+// Playlist files written inside a folder are named after it.
+pub(crate) fn playlist_base(dir: &Path, settings: &Settings) -> PathBuf {
+    let name = Path::new(dir.file_name().unwrap_or_default());
+    utils::fit_path(dir, name, PLAYLIST_EXT_RESERVE, settings.max_path_length)
+}
+
+fn write_album_sidecars(album_path: &Path, meta: &ParsedAlbumMeta, album: &AlbumResult) {
+    let nfo_path = album_path.join("album.nfo");
+    if let Err(e) = sidecars::write_album_nfo(&nfo_path, meta, album) {
+        eprintln!("Warning: Failed to write album NFO {:?}: {}", nfo_path, e);
+    }
+    let json_path = album_path.join("album.json");
+    if let Err(e) = sidecars::write_album_json(&json_path, meta, album) {
+        eprintln!("Warning: Failed to write album JSON {:?}: {}", json_path, e);
+    }
+}
+
+pub(crate) fn process_track(
     c: &mut YandexMusicClient,
+    archive: &mut DownloadArchive,
+    source: SourceKind,
     track_id: &str,
     meta: &mut ParsedAlbumMeta,
     settings: &Settings,
-    album_path: &PathBuf,
-) -> Result<(), Box<dyn Error>> {
+    album_path: &Path,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let link_mode = settings.dedupe.mode(source);
+    if link_mode != LinkMode::Off {
+        if let Some(existing) = archive.find_copy(track_id, settings.format).cloned() {
+            return reuse_track(&existing, meta, settings, album_path, link_mode);
+        }
+    }
+
+    if let Some(path) = archive.get(track_id, settings.format) {
+        println!("Track is in the download archive: {}", meta.title);
+        return Ok(path.clone());
+    }
+
     let info = c.get_file_info(track_id, settings.format.as_api_str())?;
+    if let Some(offered) = metadata::parse_quality(&info.codec, info.bitrate as u32) {
+        if let Some(path) = archive.get_offered(track_id, settings.format, offered) {
+            println!("Track is in the download archive: {}", meta.title);
+            return Ok(path.clone());
+        }
+    }
 
-    let (specs, file_ext) = metadata::parse_specs(&info.codec, info.bitrate)
-        .ok_or_else(|| format!("Unknown codec returned: {}", info.codec))?;
+    let (specs, file_ext) = parse_file_info(meta, &info)?;
 
     if meta.is_track_only {
         println!("Track 1 of 1: {} - {}", meta.title, specs);
+    } else if meta.disc_total > 1 {
+        println!("Disc {}/{}, Track {}/{}: {} - {}", meta.disc_num, meta.disc_total, meta.track_num, meta.track_total, meta.title, specs);
     } else {
         println!("Track {}/{}: {} - {}", meta.track_num, meta.track_total, meta.title, specs);
     }
 
-    let padding = utils::format_track_number(meta.track_num, meta.track_total);
-    let track_filename = utils::parse_track_template(&settings.track_template, meta, &padding);
+    if let Some(opts) = &settings.sort_names {
+        metadata::parse_sort_names(meta, opts, &settings.artist_separator);
+    }
+
+    let track_path_base = track_path_base(settings, meta, album_path, &file_ext);
+    let final_path = utils::add_extension(&track_path_base, &file_ext);
+
+    // The track template may contain subfolders.
+    if let Some(parent) = track_path_base.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    if utils::file_exists(&final_path)? {
+        println!("Track already exists locally.");
+        archive_track(archive, track_id, tags::read_quality(&final_path, &info.codec), &final_path);
+        return Ok(final_path);
+    }
+
+    // Older versions cut the name at its last dot, e.g. "01. Title.flac" was saved as "01.flac".
+    let legacy_path = track_path_base.with_extension(&file_ext);
+    if legacy_path != final_path && utils::file_exists(&legacy_path)? {
+        println!("Track already exists locally.");
+        archive_track(archive, track_id, tags::read_quality(&legacy_path, &info.codec), &legacy_path);
+        return Ok(legacy_path);
+    }
+
+    download_audio(c, &info, meta, &track_path_base, &final_path, settings)?;
+
+    if settings.embed_lyrics || settings.write_lyrics {
+        if let Err(e) = fetch_lyrics(c, track_id, meta) {
+            eprintln!("Warning: Failed to fetch lyrics: {}", e);
+        }
+    }
+    if settings.write_lyrics {
+        write_lyrics_files(&final_path, meta, settings);
+    }
+    prepare_embedded_lyrics(meta, settings);
+
+    tags::write_tags(&final_path, &info.codec, meta)?;
+    archive_track(archive, track_id, metadata::parse_quality(&info.codec, info.bitrate as u32), &final_path);
+
+    Ok(final_path)
+}
+
+// Links or copies a track that's already in the library into this source's folder,
+// along with its lyrics files. It keeps the tags of the original.
+fn reuse_track(
+    existing: &Path,
+    meta: &mut ParsedAlbumMeta,
+    settings: &Settings,
+    album_path: &Path,
+    link_mode: LinkMode,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let (_, file_ext) = parse_local_file_info(meta, existing)?;
+
+    let track_path_base = track_path_base(settings, meta, album_path, &file_ext);
+    let final_path = utils::add_extension(&track_path_base, &file_ext);
+    if final_path == existing || utils::file_exists(&final_path)? {
+        println!("Track already exists locally: {}", meta.title);
+        return Ok(final_path);
+    }
+    if let Some(parent) = final_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let used = dedupe::link_file(existing, &final_path, link_mode)?;
+    println!("Track already in library, {}: {}", match used {
+        LinkMode::Hardlink => "hard linked",
+        LinkMode::Symlink => "symlinked",
+        _ => "copied",
+    }, meta.title);
+
+    for ext in ["lrc", "txt"] {
+        let sidecar = existing.with_extension(ext);
+        if sidecar.is_file() {
+            if let Err(e) = dedupe::link_file(&sidecar, &final_path.with_extension(ext), used) {
+                eprintln!("Warning: Failed to reuse lyrics file {:?}: {}", sidecar, e);
+            }
+        }
+    }
+    Ok(final_path)
+}
 
-    let mut track_path_base = album_path.join(track_filename);
-    let mut final_path = track_path_base.with_extension(&file_ext);
+// Downloads, decrypts and muxes a track into `final_path`, untagged. With
+// verify_downloads, a file that fails verification is downloaded again with a
+// fresh link and key, up to VERIFY_ATTEMPTS times in all.
+pub(crate) fn download_audio(
+    c: &mut YandexMusicClient,
+    info: &DownloadInfo,
+    meta: &ParsedAlbumMeta,
+    track_path_base: &Path,
+    final_path: &PathBuf,
+    settings: &Settings,
+) -> Result<(), Box<dyn Error>> {
+    fetch_audio(c, info, track_path_base, final_path, settings)?;
+    if !settings.verify_downloads {
+        return Ok(());
+    }
 
-    match utils::file_exists(&final_path) {
-        Ok(true) => {
-            println!("Track already exists locally.");
+    let mut attempt = 1;
+    loop {
+        let Err(reason) = verify::verify_file(final_path, meta.duration_ms) else {
             return Ok(());
+        };
+        let _ = fs::remove_file(final_path);
+        if attempt == VERIFY_ATTEMPTS {
+            return Err(format!("Verification failed: {}", reason).into());
         }
-        Ok(false) => {}
-        Err(err) if cfg!(target_os = "windows") && err.raw_os_error() == Some(206) => {
-            track_path_base = album_path.join(padding);
-            final_path = track_path_base.with_extension(&file_ext);
-            println!("Path too long, renaming to track number only.");
+        attempt += 1;
+        println!("Verification failed ({}), downloading again ({}/{})...", reason, attempt, VERIFY_ATTEMPTS);
+
+        let retry_info = c.get_file_info(&meta.track_id, settings.format.as_api_str())?;
+        if retry_info.codec != info.codec {
+            return Err(format!("Verification failed: {}", reason).into());
         }
-        Err(err) => return Err(err.into()),
+        fetch_audio(c, &retry_info, track_path_base, final_path, settings)?;
     }
+}
 
+fn fetch_audio(
+    c: &mut YandexMusicClient,
+    info: &DownloadInfo,
+    track_path_base: &Path,
+    final_path: &PathBuf,
+    settings: &Settings,
+) -> Result<(), Box<dyn Error>> {
     let mut track_buff = get_track_data(c, &info.url)?;
 
     println!("Decrypting...");
@@ -205,44 +648,125 @@ fn process_track(
         return Err(format!("Decryption failed: {}", e).into());
     }
 
-    let tmp_dec_path = track_path_base.with_extension("dec");
+    let tmp_dec_path = utils::add_extension(track_path_base, "dec");
     fs::write(&tmp_dec_path, &track_buff)?;
 
     drop(track_buff);
 
     println!("Muxing...");
-    if let Err(e) = mux(&tmp_dec_path, &final_path, &settings.ffmpeg_path) {
-        let _ = fs::remove_file(file_ext);
+    if let Err(e) = mux(&tmp_dec_path, final_path, &settings.ffmpeg_path) {
+        let _ = fs::remove_file(final_path);
         return Err(format!("FFmpeg muxing failed: {}", e).into());
     }
 
     fs::remove_file(&tmp_dec_path)?;
+    Ok(())
+}
 
-    if let Some(has_lyrics) = meta.lyrics_avail {
-        if let Ok(lyrics_text) = get_lyrics_text(c, track_id, has_lyrics) {
-            if has_lyrics && settings.write_lyrics {
-                let lyric_path = track_path_base.with_extension("lrc");
+// Where a track is saved, without the extension.
+pub(crate) fn track_path_base(settings: &Settings, meta: &ParsedAlbumMeta, album_path: &Path, file_ext: &str) -> PathBuf {
+    let padding = utils::format_track_number(meta.track_num, meta.track_total);
+    let track_filename = utils::parse_track_template(&settings.track_template, meta, &padding, &settings.filename_policy);
 
-                if let Err(e) = fs::write(&lyric_path, &lyrics_text) {
-                    eprintln!("Warning: Failed to write lyrics file {:?}: {}", lyric_path, e);
-                } else {
-                    println!("Lyrics saved to: {:?}", lyric_path);
-                }
-            }
-            if has_lyrics {
-                meta.timed_lyrics = Some(lyrics_text);
-            } else {
-                meta.untimed_lyrics = Some(lyrics_text);
-            }
-        }
+    // Room for the longest extension used next to the track: .flac, .lrc, .dec...
+    let reserve = file_ext.len().max(3) + 1;
+    utils::fit_path(album_path, &track_filename, reserve, settings.max_path_length)
+}
+
+// Records the quality actually delivered, which can be lower than the one requested.
+fn archive_track(archive: &mut DownloadArchive, track_id: &str, quality: Option<DownloadFormat>, path: &Path) {
+    let Some(format) = quality else {
+        return;
+    };
+    if let Err(e) = archive.add(track_id, format, path) {
+        eprintln!("Warning: Failed to update download archive: {}", e);
     }
+}
 
-    tags::write_tags(&final_path, &info.codec, meta)?;
+// Fills the codec template vars, returns (specs, file extension).
+pub(crate) fn parse_file_info(meta: &mut ParsedAlbumMeta, info: &DownloadInfo) -> Result<(String, String), Box<dyn Error>> {
+    let (specs, file_ext) = metadata::parse_specs(&info.codec, info.bitrate)
+        .ok_or_else(|| format!("Unknown codec returned: {}", info.codec))?;
+    meta.codec = metadata::parse_codec_name(&info.codec).unwrap_or_default().to_string();
+    meta.bitrate = info.bitrate;
+    meta.quality = specs.clone();
+    Ok((specs, file_ext))
+}
 
+// Same for a file that's already on disk, going by its extension and bitrate.
+pub(crate) fn parse_local_file_info(meta: &mut ParsedAlbumMeta, path: &Path) -> Result<(String, String), Box<dyn Error>> {
+    let codec = path.extension()
+        .and_then(|e| e.to_str())
+        .and_then(metadata::parse_codec_from_ext)
+        .ok_or("unsupported file type")?;
+    let info = DownloadInfo {
+        key: String::new(),
+        url: String::new(),
+        bitrate: tags::read_bitrate(&path.to_path_buf(), codec).unwrap_or_default() as u16,
+        codec: codec.to_string(),
+    };
+    parse_file_info(meta, &info)
+}
+
+// Prints every template variable with its value for the first track of a link.
+pub fn list_template_vars(c: &mut YandexMusicClient, settings: &Settings, link: &MediaLink) -> Result<(), Box<dyn Error>> {
+    let (mut meta, track_id) = sample_track_meta(c, settings, link)?;
+    let info = c.get_file_info(&track_id, settings.format.as_api_str())?;
+    parse_file_info(&mut meta, &info)?;
+
+    let padding = utils::format_track_number(meta.track_num, meta.track_total);
+    let vars = utils::track_template_vars(&meta, &padding);
+    println!("{} - {}", meta.artist, meta.title);
+    for (heading, keys) in [("Album", utils::ALBUM_TEMPLATE_VARS), ("Track", utils::TRACK_ONLY_TEMPLATE_VARS)] {
+        println!("{} template variables:", heading);
+        for key in keys {
+            println!("  {{{}}} = {:?}", key, vars.get(key).map(String::as_str).unwrap_or_default());
+        }
+    }
+    println!();
     Ok(())
 }
 
-fn get_track_data(c: &mut YandexMusicClient, url: &str) -> Result<(Vec<u8>), Box<dyn Error>> {
+fn sample_track_meta(
+    c: &mut YandexMusicClient,
+    settings: &Settings,
+    link: &MediaLink,
+) -> Result<(ParsedAlbumMeta, String), Box<dyn Error>> {
+    let sep = &settings.artist_separator;
+    let (album_id, track_id) = match link {
+        MediaLink::Album { album_id } => (album_id.clone(), None),
+        MediaLink::Track { album_id, track_id } => (album_id.clone(), Some(track_id.clone())),
+        MediaLink::Artist { artist_id } => {
+            let artist = c.get_artist_meta(artist_id)?;
+            let album = artist.albums.first().ok_or("Artist has no albums")?;
+            (album.id.to_string(), None)
+        }
+        MediaLink::Playlist { uuid_or_login } => {
+            let playlist = c.get_other_user_playlist_meta(uuid_or_login)?;
+            let plist = c.get_playlist_meta(&playlist.playlist_uuid)?;
+            let (pos, track) = plist.tracks.iter()
+                .enumerate()
+                .find(|(_, t)| t.track.track_source.to_lowercase() == "own" && !t.track.albums.is_empty())
+                .map(|(i, t)| (i as u16 + 1, &t.track))
+                .ok_or("Playlist has no downloadable tracks")?;
+            let meta = playlist_track_meta(settings, &plist.title, track, pos, plist.tracks.len() as u16);
+            return Ok((meta, track.id.clone()));
+        }
+    };
+
+    let album = c.get_album_meta(&album_id)?;
+    let track_id = track_id
+        .or_else(|| album.volumes.iter().flatten().next().map(|t| t.id.clone()))
+        .ok_or("Album has no tracks")?;
+    let (disc_num, track_num, track_total, track) = metadata::find_track(&album, &track_id)
+        .ok_or("Track not found in this album")?;
+    let track_total_album: usize = album.volumes.iter().map(|v| v.len()).sum();
+    let mut meta = metadata::parse_album_meta(&album, track_total_album as u16, sep);
+    metadata::parse_track_meta(&mut meta, track, disc_num, track_num, track_total, false, sep);
+    Ok((meta, track_id))
+}
+
+fn get_track_data(c: &mut YandexMusicClient, url: &str) -> Result<Vec<u8>, Box<dyn Error>> {
     let mut resp = c.get_file_resp(url, true)?;
 
     let total_size = resp.content_length().unwrap_or(0);
@@ -290,13 +814,81 @@ fn mux(in_path: &PathBuf, out_path: &PathBuf, ffmpeg_path: &PathBuf) -> Result<(
     Ok(())
 }
 
-fn get_cover_data(c: &mut YandexMusicClient, url: &str, original: bool) -> Result<Vec<u8>, Box<ReqwestErr>> {
+// Falls back to the cover as served if it can't be processed.
+// Returns an empty cover, so nothing gets embedded, if it can't be made into a JPEG or PNG.
+pub(crate) fn prepare_embedded_cover(cover_data: Vec<u8>, settings: &Settings) -> Vec<u8> {
+    let embeddable = covers::detect_type(&cover_data).is_embeddable();
+    if embeddable && settings.cover_max_size.is_none() && settings.cover_max_kb.is_none() {
+        return cover_data;
+    }
+    match covers::prepare_for_embedding(cover_data.clone(), settings.cover_max_size, settings.cover_max_kb) {
+        Ok(data) => data,
+        Err(e) if embeddable => {
+            eprintln!("Warning: Failed to process cover: {}", e);
+            cover_data
+        }
+        Err(e) => {
+            eprintln!("Warning: Skipping cover that can't be embedded: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+pub(crate) fn get_cover_data(c: &mut YandexMusicClient, url: &str, original: bool) -> Result<Vec<u8>, Box<ReqwestErr>> {
     let to_replace = if original { "/orig" } else { "/1000x1000" };
     let full_url = format!("https://{}", url.replace("/%%", to_replace));
     let resp = c.get_file_resp(&full_url, false)?;
     Ok(resp.bytes()?.into_iter().collect())
 }
 
+// Leaves the lyrics unset if the track has none, errors only if the API request fails.
+pub(crate) fn fetch_lyrics(c: &mut YandexMusicClient, track_id: &str, meta: &mut ParsedAlbumMeta) -> Result<(), Box<dyn Error>> {
+    if let Some(has_lyrics) = meta.lyrics_avail {
+        let lyrics_text = get_lyrics_text(c, track_id, has_lyrics)?;
+        if has_lyrics {
+            meta.timed_lyrics = Some(lyrics_text);
+        } else {
+            meta.untimed_lyrics = Some(lyrics_text);
+        }
+    }
+    Ok(())
+}
+
+fn write_lyrics_file(lyric_path: &Path, lyrics_text: &str) {
+    if let Err(e) = fs::write(lyric_path, lyrics_text) {
+        eprintln!("Warning: Failed to write lyrics file {:?}: {}", lyric_path, e);
+    } else {
+        println!("Lyrics saved to: {:?}", lyric_path);
+    }
+}
+
+// Synced lyrics go to .lrc, untimed ones to .txt, next to the track file.
+pub(crate) fn write_lyrics_files(track_path: &Path, meta: &ParsedAlbumMeta, settings: &Settings) {
+    if let Some(lyrics_text) = &meta.timed_lyrics {
+        let lyrics_text = if settings.lrc_headers {
+            lyrics::add_lrc_headers(lyrics_text, meta)
+        } else {
+            lyrics_text.clone()
+        };
+        write_lyrics_file(&track_path.with_extension("lrc"), &lyrics_text);
+    }
+    if let Some(lyrics_text) = &meta.untimed_lyrics {
+        write_lyrics_file(&track_path.with_extension("txt"), lyrics_text);
+    }
+}
+
+// Applies the embedding options to the lyrics about to be written to tags.
+pub(crate) fn prepare_embedded_lyrics(meta: &mut ParsedAlbumMeta, settings: &Settings) {
+    if !settings.embed_lyrics {
+        meta.timed_lyrics = None;
+        meta.untimed_lyrics = None;
+    } else if settings.plain_lyrics {
+        if let Some(lyrics_text) = meta.timed_lyrics.take() {
+            meta.untimed_lyrics = Some(lyrics::lrc_to_plain(&lyrics_text));
+        }
+    }
+}
+
 fn get_lyrics_text(c: &mut YandexMusicClient, track_id: &str, timed: bool) -> Result<String, Box<dyn Error>> {
     let lyrics_meta = c.get_lyrics_meta(track_id, timed)?;
     let resp = c.get_file_resp(&lyrics_meta.download_url, false)?;
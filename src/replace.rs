@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::api::client::YandexMusicClient;
+use crate::api::structs::AlbumResult;
+use crate::archive::DownloadArchive;
+use crate::models::Settings;
+use crate::retag;
+use crate::{metadata, processor, tags, utils, verify};
+
+// A library file to download again in place, at the configured format.
+pub(crate) struct Replacement<'a> {
+    pub(crate) path: &'a Path,
+    pub(crate) album_id: &'a str,
+    pub(crate) track_id: &'a str,
+    // The old file is moved here, or deleted without one.
+    pub(crate) retire_to: Option<PathBuf>,
+    // Printed with the new path once it's in place, e.g. "Upgraded".
+    pub(crate) done_message: &'a str,
+}
+
+// Downloads a library file again at the configured format, with fresh tags, and
+// puts it in place of the old one.
+pub(crate) fn replace_file(
+    c: &mut YandexMusicClient,
+    settings: &Settings,
+    archive: &mut DownloadArchive,
+    p: Replacement,
+    albums: &mut HashMap<String, AlbumResult>,
+    covers: &mut HashMap<String, Vec<u8>>,
+) -> Result<(), Box<dyn Error>> {
+    let album = retag::get_album_cached(c, settings, p.album_id, albums)?;
+    let cover_uri = album.cover_uri.clone();
+    let mut meta = retag::build_track_meta(settings, album, p.track_id)
+        .ok_or("track is no longer part of its album")?;
+
+    let info = c.get_file_info(p.track_id, settings.format.as_api_str())?;
+    let (specs, file_ext) = processor::parse_file_info(&mut meta, &info)?;
+    println!("{} - {}", meta.title, specs);
+
+    if settings.write_covers && !covers.contains_key(p.album_id) {
+        if let Some(uri) = &cover_uri {
+            let cover_data = processor::get_cover_data(c, uri, settings.get_original_covers)?;
+            covers.insert(p.album_id.to_string(), processor::prepare_embedded_cover(cover_data, settings));
+        }
+    }
+    meta.cover_data = covers.get(p.album_id).cloned().unwrap_or_default();
+
+    // Downloaded next to the old file first, so a failure leaves it untouched.
+    let base = p.path.with_extension("");
+    let tmp_base = utils::add_extension(&base, "replace");
+    let tmp_path = utils::add_extension(&tmp_base, &file_ext);
+    processor::download_audio(c, &info, &meta, &tmp_base, &tmp_path, settings)?;
+
+    if settings.embed_lyrics || settings.write_lyrics {
+        if let Err(e) = processor::fetch_lyrics(c, p.track_id, &mut meta) {
+            eprintln!("Warning: Failed to fetch lyrics: {}", e);
+        }
+    }
+    processor::prepare_embedded_lyrics(&mut meta, settings);
+    // The old tags come first, so fields this tool doesn't manage survive and
+    // only the managed ones are refreshed.
+    let old_codec = p.path.extension().and_then(|e| e.to_str()).and_then(metadata::parse_codec_from_ext);
+    if let Some(old_codec) = old_codec {
+        if let Err(e) = tags::copy_tags(&p.path.to_path_buf(), old_codec, &tmp_path, &info.codec) {
+            eprintln!("Warning: Failed to carry over tags of {:?}: {}", p.path, e);
+        }
+    }
+    if let Err(e) = tags::write_tags(&tmp_path, &info.codec, &meta) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+
+    match p.retire_to {
+        Some(target) => {
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::rename(p.path, &target)?;
+        }
+        None => fs::remove_file(p.path)?,
+    }
+    let final_path = utils::add_extension(&base, &file_ext);
+    fs::rename(&tmp_path, &final_path)?;
+    if let Err(e) = verify::update_manifest(&final_path, p.path) {
+        eprintln!("Warning: Failed to update checksum manifest: {}", e);
+    }
+    if settings.write_lyrics {
+        processor::write_lyrics_files(&final_path, &meta, settings);
+    }
+
+    let quality = metadata::parse_quality(&info.codec, info.bitrate as u32).unwrap_or(settings.format);
+    if let Err(e) = archive.add(p.track_id, quality, &final_path) {
+        eprintln!("Warning: Failed to update download archive: {}", e);
+    }
+    println!("{}: {:?}", p.done_message, final_path);
+    Ok(())
+}
@@ -0,0 +1,188 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::api::client::YandexMusicClient;
+use crate::api::structs::AlbumResult;
+use crate::models::Settings;
+use crate::retag;
+use crate::{metadata, tags, utils};
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum AlbumStatus {
+    Missing,
+    Partial,
+    Complete,
+}
+
+#[derive(Serialize)]
+struct AlbumReport {
+    album_id: String,
+    title: String,
+    year: Option<u16>,
+    album_type: Option<String>,
+    status: AlbumStatus,
+    local_tracks: usize,
+    total_tracks: usize,
+}
+
+#[derive(Serialize)]
+struct ArtistReport {
+    artist_id: String,
+    name: String,
+    albums: Vec<AlbumReport>,
+}
+
+// Maps a library to Yandex albums and compares it with the full discography of
+// every album artist found, as a table or a JSON file.
+pub fn report_missing(
+    c: &mut YandexMusicClient,
+    settings: &Settings,
+    lib_path: &Path,
+    json_path: Option<&PathBuf>,
+) -> Result<(), Box<dyn Error>> {
+    // Skips .trash, .replaced and the like.
+    let files: Vec<PathBuf> = utils::find_audio_files(lib_path)?
+        .into_iter()
+        .filter(|p| {
+            !p.strip_prefix(lib_path).unwrap_or(p).components()
+                .any(|part| part.as_os_str().to_string_lossy().starts_with('.'))
+        })
+        .collect();
+    if files.is_empty() {
+        return Err("No audio files found in library".into());
+    }
+    println!("Found {} files, identifying...", files.len());
+
+    let mut albums: HashMap<String, AlbumResult> = HashMap::new();
+    let mut local: HashMap<String, HashSet<String>> = HashMap::new();
+    let mut unidentified = 0;
+    for path in &files {
+        match identify(c, settings, lib_path, path, &mut albums) {
+            Ok((album_id, track_id)) => {
+                local.entry(album_id).or_default().insert(track_id);
+            }
+            Err(e) => {
+                eprintln!("Could not identify {:?}: {}", path, e);
+                unidentified += 1;
+            }
+        }
+    }
+
+    // Album artists of everything in the library, by ID.
+    let mut artists: BTreeMap<String, String> = BTreeMap::new();
+    for album_id in local.keys() {
+        let Ok(album) = retag::get_album_cached(c, settings, album_id, &mut albums) else {
+            continue;
+        };
+        for artist in album.artists.iter().filter(|a| !a.id.is_empty()) {
+            artists.insert(artist.id.clone(), artist.name.clone());
+        }
+    }
+    println!("{} albums by {} artists in library, {} files not identified.\n", local.len(), artists.len(), unidentified);
+
+    let mut reports = Vec::new();
+    for (artist_id, name) in artists {
+        println!("Fetching discography: {}", name);
+        match artist_report(c, settings, &artist_id, &local, &mut albums) {
+            Ok(report) => reports.push(report),
+            Err(e) => eprintln!("Failed to fetch artist {}: {}", name, e),
+        }
+    }
+    reports.sort_by_key(|r| r.name.to_lowercase());
+
+    match json_path {
+        Some(path) => {
+            fs::write(path, serde_json::to_string_pretty(&reports)?)?;
+            println!("\nReport written to {:?}", path);
+        }
+        None => print_table(&reports),
+    }
+    Ok(())
+}
+
+fn identify(
+    c: &mut YandexMusicClient,
+    settings: &Settings,
+    lib_path: &Path,
+    path: &Path,
+    albums: &mut HashMap<String, AlbumResult>,
+) -> Result<(String, String), Box<dyn Error>> {
+    let codec = path.extension()
+        .and_then(|e| e.to_str())
+        .and_then(metadata::parse_codec_from_ext)
+        .ok_or("unsupported file type")?;
+    let old = tags::read_tags(&path.to_path_buf(), codec)?;
+    retag::identify_file(c, settings, lib_path, path, &old, albums)
+}
+
+fn artist_report(
+    c: &mut YandexMusicClient,
+    settings: &Settings,
+    artist_id: &str,
+    local: &HashMap<String, HashSet<String>>,
+    albums: &mut HashMap<String, AlbumResult>,
+) -> Result<ArtistReport, Box<dyn Error>> {
+    let meta = c.get_artist_meta(artist_id)?;
+    let mut report = ArtistReport {
+        artist_id: artist_id.to_string(),
+        name: meta.artist.name,
+        albums: Vec::new(),
+    };
+
+    for artist_album in meta.albums {
+        let album_id = artist_album.id.to_string();
+        let album = match retag::get_album_cached(c, settings, &album_id, albums) {
+            Ok(album) => album,
+            Err(e) => {
+                eprintln!("Skipping album {}: {}", artist_album.title, e);
+                continue;
+            }
+        };
+        let total_tracks: usize = album.volumes.iter().map(|v| v.len()).sum();
+        let local_tracks = local.get(&album_id)
+            .map(|ids| ids.iter().filter(|id| metadata::find_track(album, id).is_some()).count())
+            .unwrap_or_default();
+        let status = match local_tracks {
+            0 => AlbumStatus::Missing,
+            n if n >= total_tracks => AlbumStatus::Complete,
+            _ => AlbumStatus::Partial,
+        };
+        report.albums.push(AlbumReport {
+            album_id,
+            title: metadata::parse_title(&album.title, album.version.clone()),
+            year: album.year,
+            album_type: album.album_type.clone(),
+            status,
+            local_tracks,
+            total_tracks,
+        });
+    }
+    Ok(report)
+}
+
+fn print_table(reports: &[ArtistReport]) {
+    for report in reports {
+        let count = |status| report.albums.iter().filter(|a| a.status == status).count();
+        println!(
+            "\n{} ({} complete, {} partial, {} missing)",
+            report.name, count(AlbumStatus::Complete), count(AlbumStatus::Partial), count(AlbumStatus::Missing),
+        );
+        for album in &report.albums {
+            let status = match album.status {
+                AlbumStatus::Missing => "missing",
+                AlbumStatus::Partial => "partial",
+                AlbumStatus::Complete => "complete",
+            };
+            let year = album.year.map(|y| y.to_string()).unwrap_or_default();
+            println!(
+                "  {:<8}  {:>4}  {:>3}/{:<3}  {}  https://music.yandex.ru/album/{}",
+                status, year, album.local_tracks, album.total_tracks, album.title, album.album_id,
+            );
+        }
+    }
+}
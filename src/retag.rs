@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use crate::api::client::YandexMusicClient;
+use crate::api::structs::AlbumResult;
+use crate::models::{ParsedAlbumMeta, Settings};
+use crate::tags::{TagSummary, YANDEX_ALBUM_ID, YANDEX_TRACK_ID};
+use crate::{metadata, processor, tags, utils, verify};
+
+struct PlannedRetag {
+    path: PathBuf,
+    codec: &'static str,
+    meta: ParsedAlbumMeta,
+}
+
+pub fn retag_library(
+    c: &mut YandexMusicClient,
+    settings: &Settings,
+    lib_path: &Path,
+    dry_run: bool,
+    assume_yes: bool,
+) -> Result<(), Box<dyn Error>> {
+    let files = utils::find_audio_files(lib_path)?;
+    if files.is_empty() {
+        return Err("No audio files found in library".into());
+    }
+    println!("Found {} files, fetching metadata...", files.len());
+
+    let mut albums: HashMap<String, AlbumResult> = HashMap::new();
+    let mut covers: HashMap<String, Vec<u8>> = HashMap::new();
+    let mut planned: Vec<PlannedRetag> = Vec::new();
+    let mut unidentified = 0;
+
+    for path in files {
+        match plan_retag(c, settings, lib_path, &path, dry_run, &mut albums, &mut covers) {
+            Ok(Some(p)) => planned.push(p),
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!("Could not identify {:?}: {}", path, e);
+                unidentified += 1;
+            }
+        }
+    }
+
+    println!("\n{} files to update, {} could not be identified.", planned.len(), unidentified);
+    if dry_run && (settings.write_covers || settings.embed_lyrics || settings.write_lyrics) {
+        println!("Covers and lyrics aren't downloaded in a dry run, so changes to them aren't shown or counted.");
+    }
+    if planned.is_empty() || dry_run {
+        return Ok(());
+    }
+
+    if !assume_yes && !utils::confirm("Apply changes?") {
+        println!("Aborted.");
+        return Ok(());
+    }
+
+    for mut p in planned {
+        if let Some(cover) = covers.get(&p.meta.album_id) {
+            p.meta.cover_data = cover.clone();
+        }
+        if settings.write_lyrics {
+            processor::write_lyrics_files(&p.path, &p.meta, settings);
+        }
+        processor::prepare_embedded_lyrics(&mut p.meta, settings);
+        if let Err(e) = tags::write_tags(&p.path, p.codec, &p.meta) {
+            eprintln!("Failed to write tags to {:?}: {}", p.path, e);
+            continue;
+        }
+        if let Err(e) = verify::update_manifest(&p.path, &p.path) {
+            eprintln!("Warning: Failed to update checksum manifest: {}", e);
+        }
+        println!("Retagged: {:?}", p.path);
+    }
+    Ok(())
+}
+
+// Works out the new tags for a file and prints how they differ from the current ones.
+// Returns None if nothing would change. A dry run only compares the text fields
+// and doesn't download covers or lyrics.
+fn plan_retag(
+    c: &mut YandexMusicClient,
+    settings: &Settings,
+    lib_path: &Path,
+    path: &Path,
+    dry_run: bool,
+    albums: &mut HashMap<String, AlbumResult>,
+    covers: &mut HashMap<String, Vec<u8>>,
+) -> Result<Option<PlannedRetag>, Box<dyn Error>> {
+    let path = path.to_path_buf();
+    let codec = path.extension()
+        .and_then(|e| e.to_str())
+        .and_then(metadata::parse_codec_from_ext)
+        .ok_or("unsupported file type")?;
+
+    let old = tags::read_tags(&path, codec)?;
+    let (album_id, track_id) = identify_file(c, settings, lib_path, &path, &old, albums)?;
+
+    let album = get_album_cached(c, settings, &album_id, albums)?;
+    let mut meta = build_track_meta(settings, album, &track_id)
+        .ok_or("track is no longer part of its album")?;
+
+    let compare_covers = settings.write_covers && !dry_run;
+    let compare_lyrics = (settings.embed_lyrics || settings.write_lyrics) && !dry_run;
+
+    if compare_covers && !covers.contains_key(&album_id) {
+        if let Some(uri) = &album.cover_uri {
+            let cover_data = processor::get_cover_data(c, uri, settings.get_original_covers)?;
+            covers.insert(album_id.clone(), processor::prepare_embedded_cover(cover_data, settings));
+        }
+    }
+
+    if compare_lyrics {
+        processor::fetch_lyrics(c, &track_id, &mut meta)?;
+    }
+
+    // Compare against what will actually be embedded. The planned meta
+    // stays without a cover so only one copy per album is kept in memory.
+    let mut preview = meta.clone();
+    preview.cover_data = covers.get(&album_id).cloned().unwrap_or_default();
+    processor::prepare_embedded_lyrics(&mut preview, settings);
+    let new = tags::summarise_meta(&preview);
+
+    if !print_diff(&path, &old, &new, compare_covers, compare_lyrics) {
+        return Ok(None);
+    }
+    Ok(Some(PlannedRetag { path, codec, meta }))
+}
+
+// Returns (album ID, track ID) of a library file, preferring the IDs embedded in its tags.
+pub(crate) fn identify_file(
+    c: &mut YandexMusicClient,
+    settings: &Settings,
+    lib_path: &Path,
+    path: &Path,
+    old: &TagSummary,
+    albums: &mut HashMap<String, AlbumResult>,
+) -> Result<(String, String), Box<dyn Error>> {
+    match (old.get(YANDEX_ALBUM_ID), old.get(YANDEX_TRACK_ID)) {
+        (Some(album_id), Some(track_id)) => Ok((album_id.clone(), track_id.clone())),
+        _ => identify_by_tags(c, settings, lib_path, path, old, albums)?
+            .ok_or_else(|| "no embedded Yandex IDs and no matching track found".into()),
+    }
+}
+
+pub(crate) fn get_album_cached<'a>(
+    c: &mut YandexMusicClient,
+    settings: &Settings,
+    album_id: &str,
+    albums: &'a mut HashMap<String, AlbumResult>,
+) -> Result<&'a AlbumResult, Box<dyn Error>> {
+    if !albums.contains_key(album_id) {
+        let album = c.get_album_meta(album_id)?;
+        if !album.available {
+            return Err("Album is unavailable".into());
+        }
+        albums.insert(album_id.to_string(), album);
+        if settings.sleep {
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+    Ok(&albums[album_id])
+}
+
+pub(crate) fn build_track_meta(settings: &Settings, album: &AlbumResult, track_id: &str) -> Option<ParsedAlbumMeta> {
+    let (disc_num, track_num, track_total, track) = metadata::find_track(album, track_id)?;
+    let mut meta = metadata::parse_album_meta(album, track_total, &settings.artist_separator);
+    metadata::parse_track_meta(&mut meta, track, disc_num, track_num, track_total, false, &settings.artist_separator);
+    if let Some(opts) = &settings.sort_names {
+        metadata::parse_sort_names(&mut meta, opts, &settings.artist_separator);
+    }
+    Some(meta)
+}
+
+// Files downloaded before IDs were embedded are looked up with the search API.
+// A candidate must match the title and album tags; if several do, the one whose
+// rendered track template matches the file name wins.
+fn identify_by_tags(
+    c: &mut YandexMusicClient,
+    settings: &Settings,
+    lib_path: &Path,
+    path: &Path,
+    old: &TagSummary,
+    albums: &mut HashMap<String, AlbumResult>,
+) -> Result<Option<(String, String)>, Box<dyn Error>> {
+    let (Some(title), Some(album_title)) = (old.get("TITLE"), old.get("ALBUM")) else {
+        return Ok(None);
+    };
+    let artist = old.get("ARTIST").map(|a| a.replace("; ", " ")).unwrap_or_default();
+    let query = format!("{} {}", artist, title);
+
+    let candidates: Vec<(String, String)> = c.search_tracks(query.trim())?
+        .into_iter()
+        .filter(|t| metadata::parse_title(&t.title, t.version.clone()).to_lowercase() == title.to_lowercase())
+        .filter_map(|t| {
+            t.albums.iter()
+                .find(|a| metadata::parse_title(&a.title, a.version.clone()).to_lowercase() == album_title.to_lowercase())
+                .map(|a| (a.id.clone(), t.id.clone()))
+        })
+        .collect();
+
+    let stem = path.file_stem().unwrap_or_default();
+    let ext_len = path.extension().map(|e| e.len()).unwrap_or_default();
+    for (album_id, track_id) in &candidates {
+        let Ok(album) = get_album_cached(c, settings, album_id, albums) else {
+            continue;
+        };
+        let Some(meta) = build_track_meta(settings, album, track_id) else {
+            continue;
+        };
+        let padding = utils::format_track_number(meta.track_num, meta.track_total);
+        let rendered = utils::parse_track_template(&settings.track_template, &meta, &padding, &settings.filename_policy);
+        let reserve = ext_len.max(3) + 1;
+        let rendered = utils::fit_path(lib_path, &rendered, reserve, settings.max_path_length);
+        if rendered.file_name() == Some(stem) {
+            return Ok(Some((album_id.clone(), track_id.clone())));
+        }
+    }
+
+    if candidates.len() == 1 {
+        return Ok(candidates.into_iter().next());
+    }
+    Ok(None)
+}
+
+// Prints changed fields, returns false if there are none.
+fn print_diff(path: &Path, old: &TagSummary, new: &TagSummary, compare_covers: bool, compare_lyrics: bool) -> bool {
+    let mut changes = Vec::new();
+    for (key, value) in new {
+        if (*key == "COVER" && !compare_covers) || (*key == "LYRICS" && !compare_lyrics) {
+            continue;
+        }
+        match old.get(key) {
+            Some(old_value) if old_value == value => {}
+            Some(old_value) => changes.push(format!("  {}: {:?} -> {:?}", key, old_value, value)),
+            None => changes.push(format!("  {}: + {:?}", key, value)),
+        }
+    }
+
+    if changes.is_empty() {
+        return false;
+    }
+    println!("{:?}", path);
+    for change in changes {
+        println!("{}", change);
+    }
+    true
+}
@@ -0,0 +1,186 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::api::structs::{AlbumResult, ArtistMetaArtist};
+use crate::metadata;
+use crate::models::ParsedAlbumMeta;
+
+const XML_HEADER: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n";
+
+pub(crate) fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn push_element(xml: &mut String, name: &str, value: &str) {
+    if !value.is_empty() {
+        xml.push_str(&format!("  <{0}>{1}</{0}>\n", name, xml_escape(value)));
+    }
+}
+
+// Kodi/Jellyfin style artist.nfo.
+pub fn write_artist_nfo(path: &Path, artist: &ArtistMetaArtist) -> Result<(), Box<dyn Error>> {
+    let mut xml = String::from(XML_HEADER);
+    xml.push_str("<artist>\n");
+
+    push_element(&mut xml, "name", &artist.name);
+    for genre in &artist.genres {
+        push_element(&mut xml, "genre", genre);
+    }
+    if let Some(description) = &artist.description {
+        push_element(&mut xml, "biography", &description.text);
+    }
+    push_element(&mut xml, "yandexartistid", &artist.id);
+    if !artist.id.is_empty() {
+        push_element(&mut xml, "url", &format!("https://music.yandex.ru/artist/{}", artist.id));
+    }
+    for link in &artist.links {
+        xml.push_str(&format!(
+            "  <link type=\"{}\" title=\"{}\">{}</link>\n",
+            xml_escape(link.link_type.as_deref().unwrap_or_default()),
+            xml_escape(&link.title),
+            xml_escape(&link.href),
+        ));
+    }
+
+    xml.push_str("</artist>\n");
+    fs::write(path, xml)?;
+    Ok(())
+}
+
+fn format_duration(ms: u64) -> String {
+    let secs = ms / 1000;
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+// Kodi/Jellyfin style album.nfo.
+pub fn write_album_nfo(path: &Path, meta: &ParsedAlbumMeta, album: &AlbumResult) -> Result<(), Box<dyn Error>> {
+    let mut xml = String::from(XML_HEADER);
+    xml.push_str("<album>\n");
+
+    push_element(&mut xml, "title", &meta.album_title);
+    for artist in &meta.album_artists {
+        push_element(&mut xml, "artist", artist);
+    }
+    push_element(&mut xml, "albumartist", &meta.album_artist);
+    if let Some(genre) = &meta.genre {
+        push_element(&mut xml, "genre", genre);
+    }
+    push_element(&mut xml, "label", &meta.label);
+    if let Some(album_type) = &meta.album_type {
+        push_element(&mut xml, "releasetype", album_type);
+    }
+    if let Some(year) = meta.year {
+        push_element(&mut xml, "year", &year.to_string());
+    }
+    if let Some(date) = &meta.release_date {
+        push_element(&mut xml, "releasedate", date);
+    }
+    if let Some(year) = meta.original_year {
+        push_element(&mut xml, "originalreleasedate", &year.to_string());
+    }
+    push_element(&mut xml, "yandexalbumid", &meta.album_id);
+    push_element(&mut xml, "url", &format!("https://music.yandex.ru/album/{}", meta.album_id));
+
+    for (disc_idx, volume) in album.volumes.iter().enumerate() {
+        for (track_idx, track) in volume.iter().enumerate() {
+            xml.push_str("  <track>\n");
+            xml.push_str(&format!("    <disc>{}</disc>\n", disc_idx + 1));
+            xml.push_str(&format!("    <position>{}</position>\n", track_idx + 1));
+            xml.push_str(&format!(
+                "    <title>{}</title>\n",
+                xml_escape(&metadata::parse_title(&track.title, track.version.clone())),
+            ));
+            if let Some(ms) = track.duration_ms {
+                xml.push_str(&format!("    <duration>{}</duration>\n", format_duration(ms)));
+            }
+            xml.push_str("  </track>\n");
+        }
+    }
+
+    xml.push_str("</album>\n");
+    fs::write(path, xml)?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct ParsedTrack {
+    disc: u16,
+    position: u16,
+    track_id: String,
+    title: String,
+    artists: Vec<String>,
+    duration_ms: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct ParsedAlbum<'a> {
+    album_id: &'a str,
+    title: &'a str,
+    album_artist: &'a str,
+    album_artists: &'a [String],
+    album_artist_ids: &'a [String],
+    genre: Option<&'a str>,
+    label: &'a str,
+    album_type: Option<&'a str>,
+    year: Option<u16>,
+    release_date: Option<&'a str>,
+    original_year: Option<u16>,
+    copyright: Option<&'a str>,
+    disc_total: u16,
+    tracks: Vec<ParsedTrack>,
+}
+
+impl<'a> ParsedAlbum<'a> {
+    fn new(meta: &'a ParsedAlbumMeta, album: &AlbumResult) -> Self {
+        let mut tracks = Vec::new();
+        for (disc_idx, volume) in album.volumes.iter().enumerate() {
+            for (track_idx, track) in volume.iter().enumerate() {
+                tracks.push(ParsedTrack {
+                    disc: disc_idx as u16 + 1,
+                    position: track_idx as u16 + 1,
+                    track_id: track.id.clone(),
+                    title: metadata::parse_title(&track.title, track.version.clone()),
+                    artists: metadata::parse_artist_names(&track.artists),
+                    duration_ms: track.duration_ms,
+                });
+            }
+        }
+
+        Self {
+            album_id: &meta.album_id,
+            title: &meta.album_title,
+            album_artist: &meta.album_artist,
+            album_artists: &meta.album_artists,
+            album_artist_ids: &meta.album_artist_ids,
+            genre: meta.genre.as_deref(),
+            label: &meta.label,
+            album_type: meta.album_type.as_deref(),
+            year: meta.year,
+            release_date: meta.release_date.as_deref(),
+            original_year: meta.original_year,
+            copyright: meta.copyright.as_deref(),
+            disc_total: meta.disc_total,
+            tracks,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AlbumDump<'a> {
+    parsed: ParsedAlbum<'a>,
+    source: &'a AlbumResult,
+}
+
+// Normalised album metadata alongside the raw API response it came from.
+pub fn write_album_json(path: &Path, meta: &ParsedAlbumMeta, album: &AlbumResult) -> Result<(), Box<dyn Error>> {
+    let dump = AlbumDump { parsed: ParsedAlbum::new(meta, album), source: album };
+    fs::write(path, serde_json::to_string_pretty(&dump)?)?;
+    Ok(())
+}
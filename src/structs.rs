@@ -1,5 +1,6 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::path::PathBuf;
 
 
@@ -21,54 +22,265 @@ pub struct CliArgs {
     #[clap(short, long)]
     pub out_path: Option<PathBuf>,
 
+    #[clap(long, global = true)]
+    pub download_archive: Option<PathBuf>,
+
     #[clap(short, long)]
     pub sleep: bool,
 
     #[clap(long)]
     pub write_covers: bool,
 
+    #[clap(long)]
+    pub cover_max_size: Option<u32>,
+
+    #[clap(long)]
+    pub cover_max_kb: Option<u32>,
+
     #[clap(long)]
     pub write_lyrics: bool,
 
+    #[clap(long)]
+    pub no_embed_lyrics: bool,
+
+    #[clap(long)]
+    pub no_lrc_headers: bool,
+
+    #[clap(long)]
+    pub plain_lyrics: bool,
+
+    #[clap(long)]
+    pub disc_folders: bool,
+
+    #[clap(long)]
+    pub write_artist_nfo: bool,
+
+    #[clap(long)]
+    pub write_album_sidecars: bool,
+
     #[clap(long)]
     pub album_template: Option<String>,
 
     #[clap(long)]
     pub track_template: Option<String>,
 
-    #[clap(short, long, num_args = 1.., required = true)]
+    #[clap(long)]
+    pub artist_separator: Option<String>,
+
+    #[clap(long)]
+    pub playlist_template: Option<String>,
+
+    #[clap(long)]
+    pub playlist_layout: Option<String>,
+
+    #[clap(long)]
+    pub write_m3u8: bool,
+
+    #[clap(long)]
+    pub write_xspf: bool,
+
+    #[clap(long)]
+    pub batch_playlist: Option<String>,
+
+    #[clap(long)]
+    pub max_path_length: Option<usize>,
+
+    #[clap(long)]
+    pub filename_charset: Option<String>,
+
+    #[clap(long)]
+    pub translit_scheme: Option<String>,
+
+    #[clap(long)]
+    pub filename_replacement: Option<String>,
+
+    #[clap(long)]
+    pub collapse_whitespace: bool,
+
+    #[clap(long)]
+    pub no_trim_names: bool,
+
+    #[clap(long)]
+    pub write_sort_tags: bool,
+
+    #[clap(long, global = true)]
+    pub dedupe_album: Option<String>,
+
+    #[clap(long, global = true)]
+    pub dedupe_artist: Option<String>,
+
+    #[clap(long, global = true)]
+    pub dedupe_playlist: Option<String>,
+
+    #[clap(long, global = true)]
+    pub verify: bool,
+
+    #[clap(long)]
+    pub list_template_vars: bool,
+
+    #[clap(long, global = true)]
+    pub watch_interval: Option<u64>,
+
+    #[clap(long, global = true)]
+    pub watch_webhook: Option<String>,
+
+    #[clap(long, global = true)]
+    pub watch_state: Option<PathBuf>,
+
+    #[clap(short, long, num_args = 1.., global = true)]
     pub urls: Vec<String>,
+
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Subcommand, Debug)]
+pub enum CliCommand {
+    /// Re-fetch metadata and rewrite tags of already downloaded files.
+    Retag {
+        /// Library folder to scan.
+        path: PathBuf,
+
+        /// Only show what would change.
+        #[clap(long)]
+        dry_run: bool,
+
+        /// Don't ask for confirmation before writing.
+        #[clap(short, long)]
+        yes: bool,
+    },
+    /// Add lyrics to already downloaded files, either a whole library
+    /// folder or the tracks of the given URLs found in the output path.
+    Lyrics {
+        /// Library folder to scan instead of URLs.
+        path: Option<PathBuf>,
+    },
+    /// Add the tracks of an existing library to the download archive,
+    /// using the Yandex track IDs embedded in their tags.
+    ArchiveImport {
+        /// Library folder to scan.
+        path: PathBuf,
+    },
+    /// Mirror playlists into their folders: download new tracks, move removed
+    /// ones to .trash and rename the rest to match the current order.
+    Sync {
+        /// Delete removed tracks instead of moving them to .trash.
+        #[clap(long)]
+        delete: bool,
+        /// Also mirror the tracks liked by the signed-in account.
+        #[clap(long)]
+        likes: bool,
+    },
+    /// Re-download library tracks saved below the configured format when a
+    /// better version is available, keeping hand-edited tags.
+    Upgrade {
+        /// Library folder to scan.
+        path: PathBuf,
+
+        /// Only show what would be upgraded.
+        #[clap(long)]
+        dry_run: bool,
+
+        /// Don't ask for confirmation before downloading.
+        #[clap(short, long)]
+        yes: bool,
+
+        /// Delete replaced files instead of moving them to .replaced.
+        #[clap(long)]
+        delete: bool,
+    },
+    /// Check that library files decode and have the expected length, and
+    /// that they match any checksum manifests.
+    Verify {
+        /// Library folder to scan.
+        path: PathBuf,
+
+        /// Download broken files again, moving them to .broken.
+        #[clap(long)]
+        redownload: bool,
+
+        /// Write checksums.sha256 to every folder whose files all pass.
+        #[clap(long)]
+        write_manifests: bool,
+    },
+    /// Reports about an existing library.
+    Report {
+        #[command(subcommand)]
+        report: ReportCommand,
+    },
+    /// Poll the artists and playlists listed in a subscription file and
+    /// download new releases and newly added tracks as they appear.
+    Watch {
+        /// File with one artist or playlist URL per line.
+        subscriptions: PathBuf,
+
+        /// Poll every source once and exit instead of looping.
+        #[clap(long)]
+        once: bool,
+
+        /// Download everything on the first poll of a source instead of
+        /// only recording what's already there.
+        #[clap(long)]
+        download_existing: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ReportCommand {
+    /// List missing, partial and complete albums of every album artist in a
+    /// library folder.
+    Missing {
+        /// Library folder to scan.
+        path: PathBuf,
+
+        /// Write the report as JSON to this file instead of printing a table.
+        #[clap(long)]
+        json: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Default, Deserialize)]
 pub struct FileConfig {
     pub token: Option<String>,
     pub ffmpeg_path: Option<PathBuf>,
     pub format: Option<u8>,
     pub out_path: Option<PathBuf>,
+    pub download_archive: Option<PathBuf>,
     pub keep_covers: Option<bool>,
     pub get_original_covers: Option<bool>,
     pub write_covers: Option<bool>,
+    pub cover_max_size: Option<u32>,
+    pub cover_max_kb: Option<u32>,
     pub write_lyrics: Option<bool>,
+    pub embed_lyrics: Option<bool>,
+    pub lrc_headers: Option<bool>,
+    pub plain_lyrics: Option<bool>,
+    pub disc_folders: Option<bool>,
+    pub write_artist_nfo: Option<bool>,
+    pub write_album_sidecars: Option<bool>,
     pub sleep: Option<bool>,
     pub album_template: Option<String>,
     pub track_template: Option<String>,
-}
-
-impl Default for FileConfig {
-    fn default() -> Self {
-        Self{
-            token: None,
-            ffmpeg_path: None,
-            format: None,
-            out_path: None,
-            album_template: None,
-            track_template: None,
-            keep_covers: None,
-            write_covers: None,
-            get_original_covers: None,
-            write_lyrics: None,
-            sleep: None,
-        }
-    }
+    pub artist_separator: Option<String>,
+    pub playlist_template: Option<String>,
+    pub playlist_layout: Option<String>,
+    pub write_m3u8: Option<bool>,
+    pub write_xspf: Option<bool>,
+    pub batch_playlist: Option<String>,
+    pub max_path_length: Option<usize>,
+    pub filename_charset: Option<String>,
+    pub translit_scheme: Option<String>,
+    pub filename_replacement: Option<String>,
+    pub collapse_whitespace: Option<bool>,
+    pub trim_names: Option<bool>,
+    pub write_sort_tags: Option<bool>,
+    pub sort_articles: Option<Vec<String>>,
+    pub sort_overrides: Option<HashMap<String, String>>,
+    pub dedupe_album: Option<String>,
+    pub dedupe_artist: Option<String>,
+    pub dedupe_playlist: Option<String>,
+    pub verify_downloads: Option<bool>,
+    pub watch_interval: Option<u64>,
+    pub watch_webhook: Option<String>,
+    pub watch_state: Option<PathBuf>,
 }
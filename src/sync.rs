@@ -0,0 +1,469 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::api::client::YandexMusicClient;
+use crate::api::structs::{Owner, PlaylistMetaResult, PlaylistTrackItem};
+use crate::archive::DownloadArchive;
+use crate::models::{MediaLink, ParsedAlbumMeta, Settings, SourceKind};
+use crate::playlists::{self, PlaylistEntry};
+use crate::tags::YANDEX_TRACK_ID;
+use crate::{metadata, processor, tags, utils};
+
+const STATE_FILE: &str = ".ym-sync.json";
+const TRASH_DIR: &str = ".trash";
+const SIDECAR_EXTS: [&str; 2] = ["lrc", "txt"];
+const LIKES_TITLE: &str = "Likes";
+// Tracks fetched per request when syncing likes.
+const TRACKS_PER_REQUEST: usize = 100;
+
+// Which file holds which track, relative to the playlist folder.
+#[derive(Serialize, Deserialize, Default)]
+struct SyncState {
+    tracks: Vec<SyncedTrack>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SyncedTrack {
+    track_id: String,
+    file: PathBuf,
+    #[serde(default)]
+    file_info: Option<FileInfo>,
+}
+
+// The codec template vars a file was named with, so renames don't depend on
+// what Yandex would serve today. Read from the file when missing.
+#[derive(Serialize, Deserialize, Clone)]
+struct FileInfo {
+    codec: String,
+    bitrate: u16,
+    quality: String,
+}
+
+impl FileInfo {
+    fn of(meta: &ParsedAlbumMeta) -> FileInfo {
+        FileInfo { codec: meta.codec.clone(), bitrate: meta.bitrate, quality: meta.quality.clone() }
+    }
+
+    fn apply(&self, meta: &mut ParsedAlbumMeta) {
+        meta.codec = self.codec.clone();
+        meta.bitrate = self.bitrate;
+        meta.quality = self.quality.clone();
+    }
+}
+
+struct KnownTrack {
+    path: PathBuf,
+    file_info: Option<FileInfo>,
+}
+
+#[derive(Default)]
+struct SyncSummary {
+    added: Vec<String>,
+    removed: Vec<String>,
+    renamed: usize,
+    failed: Vec<String>,
+}
+
+// Mirrors playlists, and with `likes` the account's liked tracks, into their flat
+// folders: new tracks are downloaded, removed ones moved to .trash (or deleted) and
+// the rest renamed to match the current order.
+pub fn sync_playlists(
+    c: &mut YandexMusicClient,
+    settings: &Settings,
+    archive: &mut DownloadArchive,
+    delete_removed: bool,
+    likes: bool,
+) -> Result<(), Box<dyn Error>> {
+    archive.disable_skipping();
+    for link in &settings.media_links {
+        let MediaLink::Playlist { uuid_or_login } = link else {
+            eprintln!("Only playlists can be synced, skipping {:?}", link);
+            continue;
+        };
+        let result = processor::get_public_playlist(c, uuid_or_login)
+            .and_then(|meta| sync_playlist(c, settings, archive, meta, delete_removed));
+        if let Err(e) = result {
+            eprintln!("Error syncing playlist: {}", e);
+        }
+    }
+    if likes {
+        let result = get_likes(c)
+            .and_then(|meta| sync_playlist(c, settings, archive, meta, delete_removed));
+        if let Err(e) = result {
+            eprintln!("Error syncing likes: {}", e);
+        }
+    }
+    Ok(())
+}
+
+// Liked tracks as a playlist of the signed-in account, newest first.
+fn get_likes(c: &mut YandexMusicClient) -> Result<PlaylistMetaResult, Box<dyn Error>> {
+    let track_ids = c.get_liked_track_ids()?;
+    let mut tracks = Vec::new();
+    for chunk in track_ids.chunks(TRACKS_PER_REQUEST) {
+        tracks.extend(c.get_tracks(chunk)?.into_iter().map(|track| PlaylistTrackItem { track }));
+    }
+    Ok(PlaylistMetaResult {
+        available: true,
+        owner: Owner { login: c.login.clone() },
+        title: LIKES_TITLE.to_string(),
+        tracks,
+    })
+}
+
+fn sync_playlist(
+    c: &mut YandexMusicClient,
+    settings: &Settings,
+    archive: &mut DownloadArchive,
+    meta: PlaylistMetaResult,
+    delete_removed: bool,
+) -> Result<(), Box<dyn Error>> {
+    println!("Syncing playlist: {} - {}", meta.owner.login, meta.title);
+
+    let plist_path = processor::playlist_path(settings, &meta);
+    fs::create_dir_all(&plist_path)?;
+    let state_path = plist_path.join(STATE_FILE);
+    let mut known = load_state(&state_path, &plist_path)?;
+    let mut summary = SyncSummary::default();
+
+    let track_total = meta.tracks.len() as u16;
+    let mut wanted = Vec::new();
+    let mut entries = Vec::new();
+    for (i, t) in meta.tracks.iter().enumerate() {
+        let track = &t.track;
+        let available = track.track_source.to_lowercase() == "own" && track.available
+            && !track.albums.is_empty() && track.albums[0].available;
+        // Files already synced are kept and renamed while the track is unavailable.
+        let keep = known.contains_key(&track.id) && !track.albums.is_empty();
+        if !available && !keep {
+            entries.push(PlaylistEntry::skipped(
+                metadata::parse_artists(&track.artists, &settings.artist_separator),
+                metadata::parse_title(&track.title, track.version.clone()),
+                "unavailable",
+            ));
+            continue;
+        }
+        let track_meta = processor::playlist_track_meta(settings, &meta.title, track, i as u16 + 1, track_total);
+        wanted.push((track, track_meta));
+    }
+
+    // Removed from the playlist. Anything still listed stays, whatever its availability.
+    let listed: HashSet<&str> = meta.tracks.iter().map(|t| t.track.id.as_str()).collect();
+    let removed: Vec<String> = known.keys().filter(|id| !listed.contains(id.as_str())).cloned().collect();
+    for track_id in removed {
+        if let Some(known_track) = known.remove(&track_id) {
+            remove_track(&plist_path, &known_track.path, delete_removed)?;
+            summary.removed.push(file_label(&known_track.path));
+        }
+    }
+
+    // Still there, possibly at a new position.
+    let mut moves = Vec::new();
+    for (track, track_meta) in &wanted {
+        let Some(current) = known.get_mut(&track.id) else {
+            continue;
+        };
+        let mut track_meta = track_meta.clone();
+        match &current.file_info {
+            Some(info) => info.apply(&mut track_meta),
+            None => {
+                if let Err(e) = processor::parse_local_file_info(&mut track_meta, &current.path) {
+                    eprintln!("Warning: Can't rename {:?}: {}", current.path, e);
+                    continue;
+                }
+                current.file_info = Some(FileInfo::of(&track_meta));
+            }
+        }
+        let ext = current.path.extension().and_then(|e| e.to_str()).unwrap_or_default().to_string();
+        let base = processor::track_path_base(settings, &track_meta, &plist_path, &ext);
+        let target = utils::add_extension(&base, &ext);
+        if target != current.path {
+            moves.push((track.id.clone(), current.path.clone(), target));
+        }
+    }
+    let moves = skip_blocked_moves(moves);
+    let moved = move_tracks(&moves);
+    if moved.is_ok() {
+        summary.renamed = moves.len();
+        for (track_id, _, target) in moves {
+            if let Some(known_track) = known.get_mut(&track_id) {
+                known_track.path = target;
+            }
+        }
+    }
+    // Saved before downloading, so removals and renames are on record even if a later step fails.
+    save_state(&state_path, &plist_path, &meta.tracks, &known)?;
+    moved?;
+
+    // New in the playlist. Tracks already elsewhere in the library are linked
+    // in when deduplication is on for playlists.
+    for (track, track_meta) in &mut wanted {
+        if known.contains_key(&track.id) {
+            continue;
+        }
+        if let Some(uri) = &track.cover_uri {
+            if let Ok(cover_data) = processor::get_cover_data(c, uri, settings.get_original_covers) {
+                track_meta.cover_data = processor::prepare_embedded_cover(cover_data, settings);
+            }
+        }
+        match processor::process_track(c, archive, SourceKind::Playlist, &track.id, track_meta, settings, &plist_path) {
+            Ok(path) => {
+                known.insert(track.id.clone(), KnownTrack { path, file_info: Some(FileInfo::of(track_meta)) });
+                summary.added.push(describe(track_meta));
+            }
+            Err(e) => {
+                eprintln!("Track failed: {}", e);
+                summary.failed.push(describe(track_meta));
+            }
+        }
+        track_meta.cover_data = Vec::new();
+    }
+
+    save_state(&state_path, &plist_path, &meta.tracks, &known)?;
+
+    entries.extend(wanted.iter().filter_map(|(track, track_meta)| {
+        known.get(&track.id).map(|known_track| PlaylistEntry::track(known_track.path.clone(), track_meta))
+    }));
+    let plist_title = format!("{} - {}", meta.owner.login, meta.title);
+    let plist_base = processor::playlist_base(&plist_path, settings);
+    if let Err(e) = playlists::write_playlist_files(&plist_base, &plist_title, &entries, settings.write_m3u8, settings.write_xspf) {
+        eprintln!("Warning: Failed to write playlist file: {}", e);
+    }
+
+    print_summary(&summary, delete_removed);
+    Ok(())
+}
+
+// Track ID -> file. Folders synced for the first time are matched by embedded IDs.
+fn load_state(state_path: &Path, plist_path: &Path) -> Result<HashMap<String, KnownTrack>, Box<dyn Error>> {
+    let mut known = HashMap::new();
+    if state_path.exists() {
+        let state: SyncState = serde_json::from_str(&fs::read_to_string(state_path)?)?;
+        for t in state.tracks {
+            let path = plist_path.join(t.file);
+            if path.exists() {
+                known.insert(t.track_id, KnownTrack { path, file_info: t.file_info });
+            }
+        }
+        return Ok(known);
+    }
+
+    let trash = plist_path.join(TRASH_DIR);
+    for path in utils::find_audio_files(plist_path)? {
+        if path.starts_with(&trash) {
+            continue;
+        }
+        let codec = path.extension()
+            .and_then(|e| e.to_str())
+            .and_then(metadata::parse_codec_from_ext);
+        let track_id = codec
+            .and_then(|codec| tags::read_tags(&path, codec).ok())
+            .and_then(|summary| summary.get(YANDEX_TRACK_ID).cloned());
+        if let Some(track_id) = track_id {
+            known.insert(track_id, KnownTrack { path, file_info: None });
+        }
+    }
+    Ok(known)
+}
+
+// Known files in playlist order.
+fn save_state(
+    state_path: &Path,
+    plist_path: &Path,
+    tracks: &[PlaylistTrackItem],
+    known: &HashMap<String, KnownTrack>,
+) -> Result<(), Box<dyn Error>> {
+    let state = SyncState {
+        tracks: tracks.iter()
+            .filter_map(|t| known.get(&t.track.id).map(|known_track| SyncedTrack {
+                track_id: t.track.id.clone(),
+                file: utils::relative_path(plist_path, &known_track.path),
+                file_info: known_track.file_info.clone(),
+            }))
+            .collect(),
+    };
+    fs::write(state_path, serde_json::to_string_pretty(&state)?)?;
+    Ok(())
+}
+
+fn with_sidecars(path: &Path) -> Vec<PathBuf> {
+    let mut paths = vec![path.to_path_buf()];
+    paths.extend(SIDECAR_EXTS.iter().map(|ext| path.with_extension(ext)).filter(|p| p.exists()));
+    paths
+}
+
+fn remove_track(plist_path: &Path, path: &Path, delete: bool) -> Result<(), Box<dyn Error>> {
+    let trash = plist_path.join(TRASH_DIR);
+    for file in with_sidecars(path) {
+        if delete {
+            fs::remove_file(&file)?;
+            continue;
+        }
+        fs::create_dir_all(&trash)?;
+        let target = trash.join(file.file_name().unwrap_or_default());
+        if target.exists() {
+            fs::remove_file(&target)?;
+        }
+        fs::rename(&file, &target)?;
+    }
+    Ok(())
+}
+
+// Renames onto files that aren't being moved themselves would overwrite them, so
+// those are left out, along with any that then land on a file that stays put.
+fn skip_blocked_moves(mut moves: Vec<(String, PathBuf, PathBuf)>) -> Vec<(String, PathBuf, PathBuf)> {
+    loop {
+        let moving: HashSet<PathBuf> = moves.iter().flat_map(|(_, from, _)| with_sidecars(from)).collect();
+        let blocked = moves.iter().position(|(_, _, to)| {
+            let mut paths = vec![to.clone()];
+            paths.extend(SIDECAR_EXTS.iter().map(|ext| to.with_extension(ext)));
+            paths.iter().any(|p| p.exists() && !moving.contains(p))
+        });
+        let Some(i) = blocked else {
+            return moves;
+        };
+        let (_, from, to) = moves.remove(i);
+        eprintln!("Warning: Can't rename {:?}, {:?} is in the way", from, to);
+    }
+}
+
+// Renamed in two steps so tracks can swap names. If a rename fails, the ones
+// already done are undone, so no file is left under its temporary name.
+fn move_tracks(moves: &[(String, PathBuf, PathBuf)]) -> Result<(), Box<dyn Error>> {
+    let mut files = Vec::new();
+    for (_, from, to) in moves {
+        for file in with_sidecars(from) {
+            let tmp = utils::add_extension(&file, "sync-tmp");
+            let ext = file.extension().unwrap_or_default().to_os_string();
+            let target = to.with_extension(ext);
+            files.push((file, tmp, target));
+        }
+    }
+
+    let mut done = Vec::new();
+    if let Err(e) = rename_staged(&files, &mut done) {
+        for (from, to) in done.into_iter().rev() {
+            if let Err(e) = fs::rename(to, from) {
+                eprintln!("Warning: Failed to restore {:?}: {}", from, e);
+            }
+        }
+        return Err(e);
+    }
+    Ok(())
+}
+
+// Records every (from, to) rename it makes in `done`.
+fn rename_staged<'a>(
+    files: &'a [(PathBuf, PathBuf, PathBuf)],
+    done: &mut Vec<(&'a Path, &'a Path)>,
+) -> Result<(), Box<dyn Error>> {
+    for (file, tmp, _) in files {
+        fs::rename(file, tmp)?;
+        done.push((file, tmp));
+    }
+    for (_, tmp, target) in files {
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if target.exists() {
+            return Err(format!("{:?} already exists", target).into());
+        }
+        fs::rename(tmp, target)?;
+        done.push((tmp, target));
+    }
+    Ok(())
+}
+
+fn file_label(path: &Path) -> String {
+    path.file_name().unwrap_or_default().to_string_lossy().to_string()
+}
+
+fn describe(meta: &ParsedAlbumMeta) -> String {
+    format!("{} - {}", meta.artist, meta.title)
+}
+
+fn print_summary(summary: &SyncSummary, deleted: bool) {
+    println!(
+        "\n{} added, {} removed, {} renamed, {} failed.",
+        summary.added.len(), summary.removed.len(), summary.renamed, summary.failed.len(),
+    );
+    for name in &summary.added {
+        println!("  + {}", name);
+    }
+    let removed_to = if deleted { "deleted" } else { "moved to .trash" };
+    for name in &summary.removed {
+        println!("  - {} ({})", name, removed_to);
+    }
+    for name in &summary.failed {
+        println!("  ! {}", name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn touch(dir: &Path, name: &str) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, name).unwrap();
+        path
+    }
+
+    fn read(dir: &Path, name: &str) -> String {
+        fs::read_to_string(dir.join(name)).unwrap()
+    }
+
+    #[test]
+    fn swaps_files_with_their_sidecars() {
+        let dir = utils::test_dir("sync-swap");
+        let a = touch(&dir, "01 A.flac");
+        let b = touch(&dir, "02 B.flac");
+        touch(&dir, "01 A.lrc");
+        let moves = vec![("1".to_string(), a.clone(), b.clone()), ("2".to_string(), b.clone(), a.clone())];
+        move_tracks(&skip_blocked_moves(moves)).unwrap();
+        assert_eq!(read(&dir, "01 A.flac"), "02 B.flac");
+        assert_eq!(read(&dir, "02 B.flac"), "01 A.flac");
+        assert_eq!(read(&dir, "02 B.lrc"), "01 A.lrc");
+        assert!(!dir.join("01 A.lrc").exists());
+    }
+
+    #[test]
+    fn skips_renames_onto_untracked_files() {
+        let dir = utils::test_dir("sync-blocked");
+        let a = touch(&dir, "01 A.flac");
+        let b = touch(&dir, "02 B.flac");
+        touch(&dir, "03 Mine.flac");
+        touch(&dir, "04 Lyrics.lrc");
+        let moves = vec![
+            // Onto a file sync doesn't know about.
+            ("1".to_string(), a.clone(), dir.join("03 Mine.flac")),
+            // Onto the file that now stays put.
+            ("2".to_string(), b.clone(), a.clone()),
+            // Onto an unrelated sidecar.
+            ("3".to_string(), dir.join("05 C.flac"), dir.join("04 Lyrics.flac")),
+        ];
+        assert!(skip_blocked_moves(moves).is_empty());
+    }
+
+    #[test]
+    fn failed_renames_are_rolled_back() {
+        let dir = utils::test_dir("sync-rollback");
+        let a = touch(&dir, "01 A.flac");
+        let b = touch(&dir, "02 B.flac");
+        touch(&dir, "03 Mine.flac");
+        let moves = vec![
+            ("1".to_string(), a.clone(), dir.join("04 A.flac")),
+            ("2".to_string(), b.clone(), dir.join("03 Mine.flac")),
+        ];
+        assert!(move_tracks(&moves).is_err());
+        assert_eq!(read(&dir, "01 A.flac"), "01 A.flac");
+        assert_eq!(read(&dir, "02 B.flac"), "02 B.flac");
+        assert_eq!(read(&dir, "03 Mine.flac"), "03 Mine.flac");
+        assert!(!dir.join("04 A.flac").exists());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+    }
+}
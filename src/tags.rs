@@ -1,14 +1,77 @@
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::path::PathBuf;
 
-use id3::frame::Picture as Mp3Image;
+use id3::frame::{
+    Comment as Mp3Comment, ExtendedText as Mp3ExtText, Lyrics as Mp3Lyrics, Picture as Mp3Image,
+    SynchronisedLyrics as Mp3SyncLyrics, SynchronisedLyricsType, TimestampFormat,
+};
 use id3::frame::PictureType::CoverFront as MP3CoverFront;
-use id3::{Error as ID3Error, Tag as Mp3Tag, TagLike, Version};
+use id3::{Error as ID3Error, Tag as Mp3Tag, TagLike, Timestamp, Version};
 use metaflac::block::PictureType::CoverFront as FLACCoverFront;
 use metaflac::{Error as FlacError, Tag as FlacTag};
-use mp4ameta::{Data as Mp4Data, Error as MP4Error, Fourcc, Tag as Mp4Tag};
+use mp4ameta::{AdvisoryRating, Data as Mp4Data, Error as MP4Error, Fourcc, FreeformIdent, Tag as Mp4Tag};
 
-use crate::models::ParsedAlbumMeta;
+use crate::covers::{self, CoverType};
+use crate::lyrics;
+use crate::metadata;
+use crate::models::{DownloadFormat, ParsedAlbumMeta};
+use crate::verify;
+
+// Custom field names used to link a file back to its Yandex source.
+// Vorbis comments and ID3 TXXX descriptions use these as-is,
+// MP4 stores them as ----:com.yandex.music:<NAME> freeform atoms.
+pub const YANDEX_MP4_MEAN: &str = "com.yandex.music";
+pub const YANDEX_TRACK_ID: &str = "YANDEX_TRACK_ID";
+pub const YANDEX_ALBUM_ID: &str = "YANDEX_ALBUM_ID";
+pub const YANDEX_ARTIST_ID: &str = "YANDEX_ARTIST_ID";
+pub const YANDEX_ALBUMARTIST_ID: &str = "YANDEX_ALBUMARTIST_ID";
+
+const YANDEX_ID_KEYS: [&str; 4] = [YANDEX_TRACK_ID, YANDEX_ALBUM_ID, YANDEX_ARTIST_ID, YANDEX_ALBUMARTIST_ID];
+
+fn yandex_ids(meta: &ParsedAlbumMeta) -> [(&'static str, Vec<String>); 4] {
+    [
+        (YANDEX_TRACK_ID, vec![meta.track_id.clone()]),
+        (YANDEX_ALBUM_ID, vec![meta.album_id.clone()]),
+        (YANDEX_ARTIST_ID, meta.artist_ids.clone()),
+        (YANDEX_ALBUMARTIST_ID, meta.album_artist_ids.clone()),
+    ]
+}
+
+// Full release date when Yandex has one, otherwise just the year.
+fn release_date(meta: &ParsedAlbumMeta) -> Option<String> {
+    meta.release_date.clone().or_else(|| meta.year.map(|y| y.to_string()))
+}
+
+fn source_url(meta: &ParsedAlbumMeta) -> Option<String> {
+    if meta.album_id.is_empty() || meta.track_id.is_empty() {
+        return None;
+    }
+    Some(metadata::parse_source_url(&meta.album_id, &meta.track_id))
+}
+
+struct SortKey {
+    vorbis: &'static str,
+    id3: &'static str,
+    mp4: [u8; 4],
+}
+
+const ARTIST_SORT: SortKey = SortKey { vorbis: "ARTISTSORT", id3: "TSOP", mp4: *b"soar" };
+const ALBUMARTIST_SORT: SortKey = SortKey { vorbis: "ALBUMARTISTSORT", id3: "TSO2", mp4: *b"soaa" };
+const TITLE_SORT: SortKey = SortKey { vorbis: "TITLESORT", id3: "TSOT", mp4: *b"sonm" };
+
+const SORT_KEYS: [&SortKey; 3] = [&ARTIST_SORT, &ALBUMARTIST_SORT, &TITLE_SORT];
+
+fn sort_names(meta: &ParsedAlbumMeta) -> Vec<(&'static SortKey, &str)> {
+    [
+        (&ARTIST_SORT, &meta.artist_sort),
+        (&ALBUMARTIST_SORT, &meta.album_artist_sort),
+        (&TITLE_SORT, &meta.title_sort),
+    ]
+    .into_iter()
+    .filter_map(|(key, value)| value.as_deref().filter(|v| !v.is_empty()).map(|v| (key, v)))
+    .collect()
+}
 
 fn set_vorbis(tag: &mut metaflac::Tag, key: &str, value: &str) {
     if !value.is_empty() {
@@ -22,19 +85,83 @@ fn set_vorbis_num(tag: &mut metaflac::Tag, key: &str, n: u16) {
     }
 }
 
+fn set_vorbis_multi(tag: &mut metaflac::Tag, key: &str, values: &[String]) {
+    let values: Vec<&String> = values.iter().filter(|v| !v.is_empty()).collect();
+    if !values.is_empty() {
+        tag.set_vorbis(key, values);
+    }
+}
+
+// Retagging replaces these fields, so values the tool no longer writes
+// (a dropped explicit flag, disabled sort names, ...) don't linger.
+// Anything else already in the file is left alone.
+// Sort names and Yandex IDs are cleared alongside these.
+const FLAC_MANAGED_KEYS: [&str; 19] = [
+    "ALBUM", "ALBUMARTIST", "ARTIST", "ARTISTS", "LABEL", "TITLE",
+    "TRACKNUMBER", "TRACKTOTAL", "DISCNUMBER", "DISCTOTAL", "GENRE", "YEAR",
+    "DATE", "ORIGINALDATE", "RELEASETYPE", "COPYRIGHT", "ISRC", "BPM",
+    "ITUNESADVISORY",
+];
+const MP3_MANAGED_FRAMES: [&str; 14] = [
+    "TALB", "TPE2", "TPE1", "TIT2", "TRCK", "TPOS", "TCON", "TYER",
+    "TDRC", "TDOR", "TCOP", "TSRC", "TBPM", "TLEN",
+];
+const MP3_MANAGED_TXXX: [&str; 3] = ["RELEASETYPE", "ITUNESADVISORY", "ARTISTS"];
+const MP4_MANAGED_FREEFORM: [&str; 2] = ["ORIGINALDATE", "RELEASETYPE"];
+
+// Lyrics are only replaced when there are new ones to write, so a retag
+// without lyrics enabled keeps whatever the lyrics command embedded.
+fn set_flac_lyrics(tag: &mut metaflac::Tag, meta: &ParsedAlbumMeta) {
+    if meta.timed_lyrics.is_none() && meta.untimed_lyrics.is_none() {
+        return;
+    }
+    tag.remove_vorbis("LYRICS");
+    tag.remove_vorbis("UNSYNCEDLYRICS");
+
+    if let Some(lyrics) = &meta.untimed_lyrics {
+        set_vorbis(tag, "UNSYNCEDLYRICS", lyrics);
+    }
+
+    if let Some(lyrics) = &meta.timed_lyrics {
+        set_vorbis(tag, "LYRICS", lyrics);
+    }
+}
+
+fn clear_flac_tags(tag: &mut metaflac::Tag) {
+    for key in FLAC_MANAGED_KEYS {
+        tag.remove_vorbis(key);
+    }
+    for key in SORT_KEYS {
+        tag.remove_vorbis(key.vorbis);
+    }
+    for key in YANDEX_ID_KEYS {
+        tag.remove_vorbis(key);
+    }
+}
+
 fn write_flac_tags(track_path: &PathBuf, meta: &ParsedAlbumMeta) -> Result<(), FlacError> {
-    let mut tag = FlacTag::read_from_path(&track_path)?;
+    let mut tag = FlacTag::read_from_path(track_path)?;
+    clear_flac_tags(&mut tag);
 
     set_vorbis(&mut tag, "ALBUM", &meta.album_title);
-    set_vorbis(&mut tag, "ALBUMARTIST", &meta.album_artist);
-    set_vorbis(&mut tag, "ARTIST", &meta.artist);
+    set_vorbis_multi(&mut tag, "ALBUMARTIST", &meta.album_artists);
+    set_vorbis_multi(&mut tag, "ARTIST", &meta.artists);
+    set_vorbis_multi(&mut tag, "ARTISTS", &meta.artists);
     set_vorbis(&mut tag, "LABEL", &meta.label);
     set_vorbis(&mut tag, "TITLE", &meta.title);
     set_vorbis_num(&mut tag, "TRACKNUMBER", meta.track_num);
     set_vorbis_num(&mut tag, "TRACKTOTAL", meta.track_total);
+    set_vorbis_num(&mut tag, "DISCNUMBER", meta.disc_num);
+    set_vorbis_num(&mut tag, "DISCTOTAL", meta.disc_total);
+
+    for (key, value) in sort_names(meta) {
+        set_vorbis(&mut tag, key.vorbis, value);
+    }
 
-    if !meta.cover_data.is_empty() {
-        tag.add_picture("image/jpeg", FLACCoverFront, meta.cover_data.clone());
+    let cover_type = covers::detect_type(&meta.cover_data);
+    if cover_type.is_embeddable() {
+        tag.remove_picture_type(FLACCoverFront);
+        tag.add_picture(cover_type.mime(), FLACCoverFront, meta.cover_data.clone());
     }
 
     if let Some(genre) = &meta.genre {
@@ -45,35 +172,144 @@ fn write_flac_tags(track_path: &PathBuf, meta: &ParsedAlbumMeta) -> Result<(), F
         set_vorbis_num(&mut tag, "YEAR", year);
     }
 
-    if let Some(lyrics) = &meta.untimed_lyrics {
-        set_vorbis(&mut tag, "UNSYNCEDLYRICS", lyrics);
+    if let Some(date) = release_date(meta) {
+        set_vorbis(&mut tag, "DATE", &date);
     }
 
-    if let Some(lyrics) = &meta.timed_lyrics {
-        set_vorbis(&mut tag, "LYRICS", lyrics);
+    if let Some(year) = meta.original_year {
+        set_vorbis_num(&mut tag, "ORIGINALDATE", year);
+    }
+
+    if let Some(album_type) = &meta.album_type {
+        set_vorbis(&mut tag, "RELEASETYPE", album_type);
+    }
+
+    if let Some(copyright) = &meta.copyright {
+        set_vorbis(&mut tag, "COPYRIGHT", copyright);
+    }
+
+    if let Some(isrc) = &meta.isrc {
+        set_vorbis(&mut tag, "ISRC", isrc);
+    }
+
+    if let Some(bpm) = meta.bpm {
+        set_vorbis_num(&mut tag, "BPM", bpm);
+    }
+
+    if meta.explicit {
+        set_vorbis(&mut tag, "ITUNESADVISORY", "1");
+    }
+
+    set_flac_lyrics(&mut tag, meta);
+
+    for (key, ids) in yandex_ids(meta) {
+        set_vorbis_multi(&mut tag, key, &ids);
+    }
+
+    if let Some(url) = source_url(meta) {
+        set_vorbis(&mut tag, "COMMENT", &url);
     }
 
     tag.save()?;
     Ok(())
 }
 
+fn add_mp3_txxx(tag: &mut Mp3Tag, description: &str, value: &str) {
+    tag.add_frame(Mp3ExtText {
+        description: description.to_string(),
+        value: value.to_string(),
+    });
+}
+
+fn add_mp3_lyrics(tag: &mut Mp3Tag, meta: &ParsedAlbumMeta) {
+    if meta.timed_lyrics.is_none() && meta.untimed_lyrics.is_none() {
+        return;
+    }
+    tag.remove("USLT");
+    tag.remove("SYLT");
+
+    if let Some(lyrics) = &meta.timed_lyrics {
+        let lang = lyrics::detect_language(lyrics).to_string();
+        let content = lyrics::parse_lrc(lyrics);
+        if !content.is_empty() {
+            tag.add_frame(Mp3SyncLyrics {
+                lang: lang.clone(),
+                timestamp_format: TimestampFormat::Ms,
+                content_type: SynchronisedLyricsType::Lyrics,
+                description: String::new(),
+                content,
+            });
+        }
+        if meta.untimed_lyrics.is_none() {
+            tag.add_frame(Mp3Lyrics {
+                lang,
+                description: String::new(),
+                text: lyrics::lrc_to_plain(lyrics),
+            });
+        }
+    }
+
+    if let Some(lyrics) = &meta.untimed_lyrics {
+        tag.add_frame(Mp3Lyrics {
+            lang: lyrics::detect_language(lyrics).to_string(),
+            description: String::new(),
+            text: lyrics.clone(),
+        });
+    }
+}
+
+fn read_mp3_tag(track_path: &PathBuf) -> Result<Mp3Tag, ID3Error> {
+    match Mp3Tag::read_from_path(track_path) {
+        Ok(tag) => Ok(tag),
+        Err(ID3Error { kind: id3::ErrorKind::NoTag, .. }) => Ok(Mp3Tag::new()),
+        Err(e) => Err(e),
+    }
+}
+
+fn clear_mp3_tags(tag: &mut Mp3Tag) {
+    for id in MP3_MANAGED_FRAMES {
+        tag.remove(id);
+    }
+    for key in SORT_KEYS {
+        tag.remove(key.id3);
+    }
+    for description in MP3_MANAGED_TXXX {
+        tag.remove_extended_text(Some(description), None);
+    }
+    for key in YANDEX_ID_KEYS {
+        tag.remove_extended_text(Some(key), None);
+    }
+}
+
 fn write_mp3_tags(track_path: &PathBuf, meta: &ParsedAlbumMeta) -> Result<(), ID3Error> {
-    let mut tag = Mp3Tag::new();
+    let mut tag = read_mp3_tag(track_path)?;
+    clear_mp3_tags(&mut tag);
 
     tag.set_album(&meta.album_title);
-    tag.set_album_artist(&meta.album_artist);
-    tag.set_artist(&meta.artist);
+    // ID3v2.4 separates multiple values with a null byte.
+    tag.set_album_artist(meta.album_artists.join("\0"));
+    tag.set_artist(meta.artists.join("\0"));
     tag.set_title(&meta.title);
     tag.set_track(meta.track_num as u32);
     tag.set_total_tracks(meta.track_total as u32);
+    if meta.disc_num > 0 {
+        tag.set_disc(meta.disc_num as u32);
+        tag.set_total_discs(meta.disc_total as u32);
+    }
 
-    if !meta.cover_data.is_empty() {
+    for (key, value) in sort_names(meta) {
+        tag.set_text(key.id3, value);
+    }
+
+    let cover_type = covers::detect_type(&meta.cover_data);
+    if cover_type.is_embeddable() {
         let pic = Mp3Image {
-            mime_type: "image/jpeg".to_string(),
+            mime_type: cover_type.mime().to_string(),
             picture_type: MP3CoverFront,
             description: String::new(),
             data: meta.cover_data.clone(),
         };
+        tag.remove_picture_by_type(MP3CoverFront);
         tag.add_frame(pic);
     }
 
@@ -85,39 +321,175 @@ fn write_mp3_tags(track_path: &PathBuf, meta: &ParsedAlbumMeta) -> Result<(), ID
         tag.set_year(year as i32);
     }
 
+    if let Some(date) = release_date(meta).and_then(|d| d.parse::<Timestamp>().ok()) {
+        tag.set_date_recorded(date);
+    }
+
+    if let Some(year) = meta.original_year {
+        tag.set_original_date_released(Timestamp {
+            year: year as i32,
+            month: None,
+            day: None,
+            hour: None,
+            minute: None,
+            second: None,
+        });
+    }
+
+    if let Some(album_type) = &meta.album_type {
+        add_mp3_txxx(&mut tag, "RELEASETYPE", album_type);
+    }
+
+    if let Some(copyright) = &meta.copyright {
+        tag.set_text("TCOP", copyright);
+    }
+
+    if let Some(isrc) = &meta.isrc {
+        tag.set_text("TSRC", isrc);
+    }
+
+    if let Some(bpm) = meta.bpm {
+        tag.set_text("TBPM", bpm.to_string());
+    }
+
+    if let Some(duration) = meta.duration_ms {
+        tag.set_duration(duration as u32);
+    }
+
+    if meta.explicit {
+        add_mp3_txxx(&mut tag, "ITUNESADVISORY", "1");
+    }
+
+    if !meta.artists.is_empty() {
+        add_mp3_txxx(&mut tag, "ARTISTS", &meta.artists.join("\0"));
+    }
+
+    add_mp3_lyrics(&mut tag, meta);
+
+    for (key, ids) in yandex_ids(meta) {
+        let ids: Vec<String> = ids.into_iter().filter(|id| !id.is_empty()).collect();
+        if !ids.is_empty() {
+            add_mp3_txxx(&mut tag, key, &ids.join("\0"));
+        }
+    }
+
+    if let Some(url) = source_url(meta) {
+        tag.remove_comment(Some(""), None);
+        tag.add_frame(Mp3Comment {
+            lang: "eng".to_string(),
+            description: String::new(),
+            text: url,
+        });
+    }
+
     tag.write_to_path(track_path, Version::Id3v24)?;
     Ok(())
 }
 
+fn set_mp4_freeform(tag: &mut Mp4Tag, name: &str, value: &str) {
+    tag.set_data(FreeformIdent::new("com.apple.iTunes", name), Mp4Data::Utf8(value.to_string()));
+}
+
+fn set_mp4_lyrics(tag: &mut Mp4Tag, meta: &ParsedAlbumMeta) {
+    if let Some(lyrics) = &meta.timed_lyrics {
+        tag.set_lyrics(lyrics);
+    } else if let Some(lyrics) = &meta.untimed_lyrics {
+        tag.set_lyrics(lyrics);
+    }
+}
+
+fn clear_mp4_tags(tag: &mut Mp4Tag) {
+    tag.remove_disc();
+    tag.remove_genres();
+    tag.remove_year();
+    tag.remove_copyright();
+    tag.remove_isrc();
+    tag.remove_bpm();
+    tag.remove_advisory_rating();
+    for key in SORT_KEYS {
+        tag.remove_data_of(&Fourcc(key.mp4));
+    }
+    for name in MP4_MANAGED_FREEFORM {
+        tag.remove_data_of(&FreeformIdent::new("com.apple.iTunes", name));
+    }
+    for key in YANDEX_ID_KEYS {
+        tag.remove_data_of(&FreeformIdent::new(YANDEX_MP4_MEAN, key));
+    }
+}
+
 fn write_mp4_tags(track_path: &PathBuf, meta: &ParsedAlbumMeta) -> Result<(), MP4Error> {
-    let mut tag = Mp4Tag::read_from_path(&track_path)?;
+    let mut tag = Mp4Tag::read_from_path(track_path)?;
+    clear_mp4_tags(&mut tag);
 
     tag.set_album(&meta.album_title);
-    tag.set_album_artist(&meta.album_artist);
-    tag.set_artist(&meta.artist);
+    tag.set_album_artists(meta.album_artists.clone());
+    tag.set_artists(meta.artists.clone());
     tag.set_title(&meta.title);
     tag.set_track(meta.track_num, meta.track_total);
+    if meta.disc_num > 0 {
+        tag.set_disc(meta.disc_num, meta.disc_total);
+    }
+
+    for (key, value) in sort_names(meta) {
+        tag.set_data(Fourcc(key.mp4), Mp4Data::Utf8(value.to_string()));
+    }
 
-    let covr = Fourcc(*b"covr");
-    if !meta.cover_data.is_empty() {
-        tag.add_data(covr, Mp4Data::Jpeg(meta.cover_data.clone()));
+    // Other image types are never embedded, so there's no fallback label.
+    let cover = match covers::detect_type(&meta.cover_data) {
+        CoverType::Jpeg => Some(Mp4Data::Jpeg(meta.cover_data.clone())),
+        CoverType::Png => Some(Mp4Data::Png(meta.cover_data.clone())),
+        _ => None,
+    };
+    if let Some(data) = cover {
+        tag.set_data(Fourcc(*b"covr"), data);
     }
 
     if let Some(genre) = &meta.genre {
         tag.set_genre(genre);
     }
 
-    if let Some(year) = meta.year {
-        tag.set_year(year.to_string());
+    if let Some(date) = release_date(meta) {
+        tag.set_year(date);
     }
 
-    if let Some(lyrics) = &meta.timed_lyrics {
-        tag.set_lyrics(lyrics);
-    } else if let Some(lyrics) = &meta.untimed_lyrics {
-        tag.set_lyrics(lyrics);
+    if let Some(year) = meta.original_year {
+        set_mp4_freeform(&mut tag, "ORIGINALDATE", &year.to_string());
+    }
+
+    if let Some(album_type) = &meta.album_type {
+        set_mp4_freeform(&mut tag, "RELEASETYPE", album_type);
+    }
+
+    if let Some(copyright) = &meta.copyright {
+        tag.set_copyright(copyright);
+    }
+
+    if let Some(isrc) = &meta.isrc {
+        tag.set_isrc(isrc);
+    }
+
+    if let Some(bpm) = meta.bpm {
+        tag.set_bpm(bpm);
+    }
+
+    if meta.explicit {
+        tag.set_advisory_rating(AdvisoryRating::Explicit);
+    }
+
+    set_mp4_lyrics(&mut tag, meta);
+
+    for (key, ids) in yandex_ids(meta) {
+        let ids: Vec<Mp4Data> = ids.into_iter().filter(|id| !id.is_empty()).map(Mp4Data::Utf8).collect();
+        if !ids.is_empty() {
+            tag.set_all_data(FreeformIdent::new(YANDEX_MP4_MEAN, key), ids);
+        }
+    }
+
+    if let Some(url) = source_url(meta) {
+        tag.set_comment(url);
     }
 
-    tag.write_to_path(&track_path)?;
+    tag.write_to_path(track_path)?;
     Ok(())
 }
 
@@ -129,4 +501,430 @@ pub fn write_tags(track_path: &PathBuf, codec: &str, meta: &ParsedAlbumMeta) ->
         _ => {}
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+// Updates only the lyrics of an already tagged file.
+pub fn write_lyrics(track_path: &PathBuf, codec: &str, meta: &ParsedAlbumMeta) -> Result<(), Box<dyn Error>> {
+    match codec {
+        "flac-mp4" => {
+            let mut tag = FlacTag::read_from_path(track_path)?;
+            set_flac_lyrics(&mut tag, meta);
+            tag.save()?;
+        }
+        "mp3" | "mp3-mp4" => {
+            let mut tag = read_mp3_tag(track_path)?;
+            add_mp3_lyrics(&mut tag, meta);
+            tag.write_to_path(track_path, Version::Id3v24)?;
+        }
+        "aac-mp4" | "he-aac-mp4" => {
+            let mut tag = Mp4Tag::read_from_path(track_path)?;
+            set_mp4_lyrics(&mut tag, meta);
+            tag.write_to_path(track_path)?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// Fields other than these aren't written by the tool and are carried over as-is
+// when a track is downloaded again, in whatever format.
+fn is_managed_key(key: &str) -> bool {
+    let key = key.to_uppercase();
+    FLAC_MANAGED_KEYS.contains(&key.as_str())
+        || MP3_MANAGED_TXXX.contains(&key.as_str())
+        || YANDEX_ID_KEYS.contains(&key.as_str())
+        || SORT_KEYS.iter().any(|k| k.vorbis == key)
+        || key == "LYRICS"
+        || key == "UNSYNCEDLYRICS"
+}
+
+// Tags of a file in a format-independent form, keyed by Vorbis comment names.
+// Free-form fields (ReplayGain, ratings, MusicBrainz IDs, ...) map to TXXX frames
+// and com.apple.iTunes atoms.
+#[derive(Default)]
+struct PortableTags {
+    fields: Vec<(String, Vec<String>)>,
+    cover: Option<Vec<u8>>,
+}
+
+const PORTABLE_MP3_FRAMES: [(&str, &str); 3] = [("COMMENT", "COMM"), ("COMPOSER", "TCOM"), ("GROUPING", "TIT1")];
+const PORTABLE_MP4_ATOMS: [(&str, [u8; 4]); 3] = [("COMMENT", *b"\xa9cmt"), ("COMPOSER", *b"\xa9wrt"), ("GROUPING", *b"\xa9grp")];
+
+fn read_portable_tags(track_path: &PathBuf, codec: &str) -> Result<PortableTags, Box<dyn Error>> {
+    let mut portable = PortableTags::default();
+    match codec {
+        "flac-mp4" => {
+            let tag = FlacTag::read_from_path(track_path)?;
+            if let Some(comments) = tag.vorbis_comments() {
+                for (key, values) in &comments.comments {
+                    portable.fields.push((key.to_uppercase(), values.clone()));
+                }
+            }
+            portable.cover = tag.pictures().find(|p| p.picture_type == FLACCoverFront).map(|p| p.data.clone());
+        }
+        "mp3" | "mp3-mp4" => {
+            let tag = read_mp3_tag(track_path)?;
+            for text in tag.extended_texts() {
+                portable.fields.push((text.description.clone(), text.value.split('\0').map(str::to_string).collect()));
+            }
+            if let Some(comment) = tag.comments().find(|c| c.description.is_empty()) {
+                portable.fields.push(("COMMENT".to_string(), vec![comment.text.clone()]));
+            }
+            for (key, id) in &PORTABLE_MP3_FRAMES[1..] {
+                if let Some(text) = tag.get(id).and_then(|f| f.content().text()) {
+                    portable.fields.push((key.to_string(), text.split('\0').map(str::to_string).collect()));
+                }
+            }
+            portable.cover = tag.pictures().find(|p| p.picture_type == MP3CoverFront).map(|p| p.data.clone());
+        }
+        "aac-mp4" | "he-aac-mp4" => {
+            let tag = Mp4Tag::read_from_path(track_path)?;
+            for (ident, _) in tag.data() {
+                if let mp4ameta::DataIdent::Freeform { mean, name } = ident {
+                    if mean == "com.apple.iTunes" {
+                        let ident = FreeformIdent::new(mean, name);
+                        portable.fields.push((name.clone(), tag.strings_of(&ident).map(str::to_string).collect()));
+                    }
+                }
+            }
+            for (key, atom) in PORTABLE_MP4_ATOMS {
+                let values: Vec<String> = tag.strings_of(&Fourcc(atom)).map(str::to_string).collect();
+                if !values.is_empty() {
+                    portable.fields.push((key.to_string(), values));
+                }
+            }
+            portable.cover = tag.artwork().map(|a| a.data.to_vec());
+        }
+        _ => {}
+    }
+    portable.fields.retain(|(key, values)| !is_managed_key(key) && !values.is_empty());
+    Ok(portable)
+}
+
+fn write_portable_tags(track_path: &PathBuf, codec: &str, portable: &PortableTags) -> Result<(), Box<dyn Error>> {
+    let cover = portable.cover.as_ref().filter(|c| covers::detect_type(c).is_embeddable());
+    match codec {
+        "flac-mp4" => {
+            let mut tag = FlacTag::read_from_path(track_path)?;
+            for (key, values) in &portable.fields {
+                tag.set_vorbis(key.clone(), values.clone());
+            }
+            if let Some(cover) = cover {
+                tag.remove_picture_type(FLACCoverFront);
+                tag.add_picture(covers::detect_type(cover).mime(), FLACCoverFront, cover.clone());
+            }
+            tag.save()?;
+        }
+        "mp3" | "mp3-mp4" => {
+            let mut tag = read_mp3_tag(track_path)?;
+            for (key, values) in &portable.fields {
+                match PORTABLE_MP3_FRAMES.iter().find(|(k, _)| k == key) {
+                    Some((_, "COMM")) => {
+                        tag.add_frame(Mp3Comment {
+                            lang: "eng".to_string(),
+                            description: String::new(),
+                            text: values.join("\n"),
+                        });
+                    }
+                    Some((_, id)) => tag.set_text(*id, values.join("\0")),
+                    None => add_mp3_txxx(&mut tag, key, &values.join("\0")),
+                }
+            }
+            if let Some(cover) = cover {
+                tag.remove_picture_by_type(MP3CoverFront);
+                tag.add_frame(Mp3Image {
+                    mime_type: covers::detect_type(cover).mime().to_string(),
+                    picture_type: MP3CoverFront,
+                    description: String::new(),
+                    data: cover.clone(),
+                });
+            }
+            tag.write_to_path(track_path, Version::Id3v24)?;
+        }
+        "aac-mp4" | "he-aac-mp4" => {
+            let mut tag = Mp4Tag::read_from_path(track_path)?;
+            for (key, values) in &portable.fields {
+                let data = values.iter().cloned().map(Mp4Data::Utf8);
+                match PORTABLE_MP4_ATOMS.iter().find(|(k, _)| k == key) {
+                    Some((_, atom)) => tag.set_all_data(Fourcc(*atom), data),
+                    None => tag.set_all_data(FreeformIdent::new("com.apple.iTunes", key), data),
+                }
+            }
+            let cover = cover.map(|c| match covers::detect_type(c) {
+                CoverType::Png => Mp4Data::Png(c.clone()),
+                _ => Mp4Data::Jpeg(c.clone()),
+            });
+            if let Some(data) = cover {
+                tag.set_data(Fourcc(*b"covr"), data);
+            }
+            tag.write_to_path(track_path)?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// Copies the tags of a file onto its re-download, so write_tags only has to refresh
+// the fields it manages. Within one format the whole tag is copied.
+pub fn copy_tags(from: &PathBuf, from_codec: &str, to: &PathBuf, to_codec: &str) -> Result<(), Box<dyn Error>> {
+    match (from_codec, to_codec) {
+        ("flac-mp4", "flac-mp4") => {
+            let old = FlacTag::read_from_path(from)?;
+            let mut tag = FlacTag::read_from_path(to)?;
+            if let Some(comments) = old.vorbis_comments() {
+                for (key, values) in &comments.comments {
+                    tag.set_vorbis(key.clone(), values.clone());
+                }
+            }
+            for picture in old.pictures() {
+                tag.add_picture(picture.mime_type.clone(), picture.picture_type, picture.data.clone());
+            }
+            tag.save()?;
+        }
+        ("mp3" | "mp3-mp4", "mp3" | "mp3-mp4") => {
+            read_mp3_tag(from)?.write_to_path(to, Version::Id3v24)?;
+        }
+        ("aac-mp4" | "he-aac-mp4", "aac-mp4" | "he-aac-mp4") => {
+            let old = Mp4Tag::read_from_path(from)?;
+            let mut tag = Mp4Tag::read_from_path(to)?;
+            for (ident, _) in old.data() {
+                tag.set_all_data(ident.clone(), old.data_of(ident).cloned());
+            }
+            tag.write_to_path(to)?;
+        }
+        _ => write_portable_tags(to, to_codec, &read_portable_tags(from, from_codec)?)?,
+    }
+    Ok(())
+}
+
+// A flat, format-independent view of the fields this tool writes,
+// used to show what a retag would change.
+pub type TagSummary = BTreeMap<&'static str, String>;
+
+fn insert_summary(summary: &mut TagSummary, key: &'static str, value: impl Into<String>) {
+    let value = value.into();
+    if !value.is_empty() && value != "0" {
+        summary.insert(key, value);
+    }
+}
+
+fn insert_summary_cover(summary: &mut TagSummary, cover: Option<usize>) {
+    if let Some(len) = cover {
+        insert_summary(summary, "COVER", format!("{} bytes", len));
+    }
+}
+
+pub fn summarise_meta(meta: &ParsedAlbumMeta) -> TagSummary {
+    let mut summary = TagSummary::new();
+    insert_summary(&mut summary, "ALBUM", meta.album_title.clone());
+    insert_summary(&mut summary, "ALBUMARTIST", meta.album_artists.join("; "));
+    insert_summary(&mut summary, "ARTIST", meta.artists.join("; "));
+    insert_summary(&mut summary, "TITLE", meta.title.clone());
+    insert_summary(&mut summary, "TRACKNUMBER", meta.track_num.to_string());
+    insert_summary(&mut summary, "TRACKTOTAL", meta.track_total.to_string());
+    insert_summary(&mut summary, "DISCNUMBER", meta.disc_num.to_string());
+    insert_summary(&mut summary, "DISCTOTAL", meta.disc_total.to_string());
+    insert_summary(&mut summary, "DATE", release_date(meta).unwrap_or_default());
+    insert_summary(&mut summary, "GENRE", meta.genre.clone().unwrap_or_default());
+    insert_summary(&mut summary, "ISRC", meta.isrc.clone().unwrap_or_default());
+    for (key, value) in sort_names(meta) {
+        insert_summary(&mut summary, key.vorbis, value);
+    }
+    insert_summary(&mut summary, YANDEX_TRACK_ID, meta.track_id.clone());
+    insert_summary(&mut summary, YANDEX_ALBUM_ID, meta.album_id.clone());
+    if meta.timed_lyrics.is_some() || meta.untimed_lyrics.is_some() {
+        insert_summary(&mut summary, "LYRICS", "yes");
+    }
+    insert_summary_cover(&mut summary, Some(meta.cover_data.len()).filter(|l| *l > 0));
+    summary
+}
+
+fn read_flac_summary(track_path: &PathBuf) -> Result<TagSummary, FlacError> {
+    let tag = FlacTag::read_from_path(track_path)?;
+    let get = |key: &str| -> String {
+        tag.get_vorbis(key).map(|v| v.collect::<Vec<&str>>().join("; ")).unwrap_or_default()
+    };
+
+    let mut summary = TagSummary::new();
+    for key in [
+        "ALBUM", "ALBUMARTIST", "ARTIST", "TITLE", "TRACKNUMBER", "TRACKTOTAL",
+        "DISCNUMBER", "DISCTOTAL", "DATE", "GENRE", "ISRC", YANDEX_TRACK_ID, YANDEX_ALBUM_ID,
+        ARTIST_SORT.vorbis, ALBUMARTIST_SORT.vorbis, TITLE_SORT.vorbis,
+    ] {
+        insert_summary(&mut summary, key, get(key));
+    }
+    if !get("LYRICS").is_empty() || !get("UNSYNCEDLYRICS").is_empty() {
+        insert_summary(&mut summary, "LYRICS", "yes");
+    }
+    insert_summary_cover(&mut summary, tag.pictures().find(|p| p.picture_type == FLACCoverFront).map(|p| p.data.len()));
+    Ok(summary)
+}
+
+fn read_mp3_summary(track_path: &PathBuf) -> Result<TagSummary, ID3Error> {
+    let tag = match Mp3Tag::read_from_path(track_path) {
+        Ok(tag) => tag,
+        Err(ID3Error { kind: id3::ErrorKind::NoTag, .. }) => return Ok(TagSummary::new()),
+        Err(e) => return Err(e),
+    };
+    let multi = |value: Option<&str>| value.unwrap_or_default().split('\0').collect::<Vec<&str>>().join("; ");
+    let txxx = |description: &str| {
+        tag.extended_texts()
+            .find(|t| t.description == description)
+            .map(|t| t.value.clone())
+            .unwrap_or_default()
+    };
+
+    let mut summary = TagSummary::new();
+    insert_summary(&mut summary, "ALBUM", tag.album().unwrap_or_default());
+    insert_summary(&mut summary, "ALBUMARTIST", multi(tag.album_artist()));
+    insert_summary(&mut summary, "ARTIST", multi(tag.artist()));
+    insert_summary(&mut summary, "TITLE", tag.title().unwrap_or_default());
+    insert_summary(&mut summary, "TRACKNUMBER", tag.track().unwrap_or_default().to_string());
+    insert_summary(&mut summary, "TRACKTOTAL", tag.total_tracks().unwrap_or_default().to_string());
+    insert_summary(&mut summary, "DISCNUMBER", tag.disc().unwrap_or_default().to_string());
+    insert_summary(&mut summary, "DISCTOTAL", tag.total_discs().unwrap_or_default().to_string());
+    insert_summary(&mut summary, "DATE", tag.date_recorded().map(|d| d.to_string()).unwrap_or_default());
+    insert_summary(&mut summary, "GENRE", tag.genre().unwrap_or_default());
+    insert_summary(&mut summary, "ISRC", tag.get("TSRC").and_then(|f| f.content().text()).unwrap_or_default());
+    for key in [&ARTIST_SORT, &ALBUMARTIST_SORT, &TITLE_SORT] {
+        insert_summary(&mut summary, key.vorbis, tag.get(key.id3).and_then(|f| f.content().text()).unwrap_or_default());
+    }
+    insert_summary(&mut summary, YANDEX_TRACK_ID, txxx(YANDEX_TRACK_ID));
+    insert_summary(&mut summary, YANDEX_ALBUM_ID, txxx(YANDEX_ALBUM_ID));
+    if tag.lyrics().next().is_some() || tag.synchronised_lyrics().next().is_some() {
+        insert_summary(&mut summary, "LYRICS", "yes");
+    }
+    insert_summary_cover(&mut summary, tag.pictures().find(|p| p.picture_type == MP3CoverFront).map(|p| p.data.len()));
+    Ok(summary)
+}
+
+fn read_mp4_summary(track_path: &PathBuf) -> Result<TagSummary, MP4Error> {
+    let tag = Mp4Tag::read_from_path(track_path)?;
+    let freeform = |name: &str| {
+        tag.strings_of(&FreeformIdent::new(YANDEX_MP4_MEAN, name)).next().unwrap_or_default().to_string()
+    };
+
+    let mut summary = TagSummary::new();
+    insert_summary(&mut summary, "ALBUM", tag.album().unwrap_or_default());
+    insert_summary(&mut summary, "ALBUMARTIST", tag.album_artists().collect::<Vec<&str>>().join("; "));
+    insert_summary(&mut summary, "ARTIST", tag.artists().collect::<Vec<&str>>().join("; "));
+    insert_summary(&mut summary, "TITLE", tag.title().unwrap_or_default());
+    insert_summary(&mut summary, "TRACKNUMBER", tag.track_number().unwrap_or_default().to_string());
+    insert_summary(&mut summary, "TRACKTOTAL", tag.total_tracks().unwrap_or_default().to_string());
+    insert_summary(&mut summary, "DISCNUMBER", tag.disc_number().unwrap_or_default().to_string());
+    insert_summary(&mut summary, "DISCTOTAL", tag.total_discs().unwrap_or_default().to_string());
+    insert_summary(&mut summary, "DATE", tag.year().unwrap_or_default());
+    insert_summary(&mut summary, "GENRE", tag.genre().unwrap_or_default());
+    insert_summary(&mut summary, "ISRC", tag.isrc().unwrap_or_default());
+    for key in [&ARTIST_SORT, &ALBUMARTIST_SORT, &TITLE_SORT] {
+        insert_summary(&mut summary, key.vorbis, tag.strings_of(&Fourcc(key.mp4)).next().unwrap_or_default());
+    }
+    insert_summary(&mut summary, YANDEX_TRACK_ID, freeform(YANDEX_TRACK_ID));
+    insert_summary(&mut summary, YANDEX_ALBUM_ID, freeform(YANDEX_ALBUM_ID));
+    if tag.lyrics().is_some() {
+        insert_summary(&mut summary, "LYRICS", "yes");
+    }
+    insert_summary_cover(&mut summary, tag.artworks().next().map(|a| a.data.len()));
+    Ok(summary)
+}
+
+// Bitrate in Kbps, 0 for lossless files.
+pub fn read_bitrate(track_path: &PathBuf, codec: &str) -> Option<u32> {
+    match codec {
+        "aac-mp4" | "he-aac-mp4" => Some(Mp4Tag::read_from_path(track_path).ok()?.avg_bitrate()? / 1000),
+        "mp3" | "mp3-mp4" => verify::read_mp3_bitrate(track_path),
+        _ => Some(0),
+    }
+}
+
+// Works out which download format a file came from.
+pub fn read_quality(track_path: &PathBuf, codec: &str) -> Option<DownloadFormat> {
+    let kbps = match codec {
+        "aac-mp4" | "he-aac-mp4" => read_bitrate(track_path, codec)?,
+        _ => 0,
+    };
+    metadata::parse_quality(codec, kbps)
+}
+
+pub fn read_tags(track_path: &PathBuf, codec: &str) -> Result<TagSummary, Box<dyn Error>> {
+    let summary = match codec {
+        "flac-mp4" => read_flac_summary(track_path)?,
+        "mp3" | "mp3-mp4" => read_mp3_summary(track_path)?,
+        "aac-mp4" | "he-aac-mp4" => read_mp4_summary(track_path)?,
+        _ => TagSummary::new(),
+    };
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use crate::utils;
+
+    // Just enough of a FLAC file for its tags to be read and written.
+    fn empty_flac(path: &PathBuf) {
+        let mut data = b"fLaC".to_vec();
+        data.extend_from_slice(&[0x80, 0, 0, 34]);
+        data.extend_from_slice(&[0; 34]);
+        fs::write(path, data).unwrap();
+    }
+
+    fn tagged_mp3(path: &PathBuf) {
+        fs::write(path, b"").unwrap();
+        let mut tag = Mp3Tag::new();
+        tag.set_title("Old title");
+        tag.set_text("TCOM", "Composer");
+        add_mp3_txxx(&mut tag, "REPLAYGAIN_TRACK_GAIN", "-6.5 dB");
+        add_mp3_txxx(&mut tag, "ARTISTS", "A\0B");
+        tag.add_frame(Mp3Comment {
+            lang: "eng".to_string(),
+            description: String::new(),
+            text: "Nice".to_string(),
+        });
+        tag.write_to_path(path, Version::Id3v24).unwrap();
+    }
+
+    #[test]
+    fn copies_the_whole_tag_within_a_format() {
+        let dir = utils::test_dir("tags-copy-mp3");
+        let (old, new) = (dir.join("old.mp3"), dir.join("new.mp3"));
+        tagged_mp3(&old);
+        fs::write(&new, b"").unwrap();
+        copy_tags(&old, "mp3", &new, "mp3").unwrap();
+
+        let tag = Mp3Tag::read_from_path(&new).unwrap();
+        assert_eq!(tag.title(), Some("Old title"));
+        assert_eq!(tag.get("TCOM").and_then(|f| f.content().text()), Some("Composer"));
+        assert!(tag.extended_texts().any(|t| t.description == "REPLAYGAIN_TRACK_GAIN"));
+        assert_eq!(tag.comments().next().map(|c| c.text.as_str()), Some("Nice"));
+    }
+
+    #[test]
+    fn carries_unmanaged_fields_across_formats() {
+        let dir = utils::test_dir("tags-copy-cross");
+        let (old, new) = (dir.join("old.mp3"), dir.join("new.flac"));
+        tagged_mp3(&old);
+        empty_flac(&new);
+        copy_tags(&old, "mp3", &new, "flac-mp4").unwrap();
+
+        let tag = FlacTag::read_from_path(&new).unwrap();
+        let field = |key| tag.get_vorbis(key).map(|v| v.collect::<Vec<&str>>());
+        assert_eq!(field("REPLAYGAIN_TRACK_GAIN"), Some(vec!["-6.5 dB"]));
+        assert_eq!(field("COMPOSER"), Some(vec!["Composer"]));
+        assert_eq!(field("COMMENT"), Some(vec!["Nice"]));
+        // Refreshed by write_tags instead.
+        assert_eq!(field("TITLE"), None);
+        assert_eq!(field("ARTISTS"), None);
+    }
+
+    #[test]
+    fn managed_keys_ignore_case() {
+        assert!(is_managed_key("title"));
+        assert!(is_managed_key("ALBUMARTISTSORT"));
+        assert!(is_managed_key("Lyrics"));
+        assert!(!is_managed_key("REPLAYGAIN_TRACK_GAIN"));
+        assert!(!is_managed_key("MUSICBRAINZ_TRACKID"));
+    }
+}
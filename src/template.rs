@@ -0,0 +1,343 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::PathBuf;
+use std::str::Chars;
+
+use crate::models::FilenamePolicy;
+use crate::utils;
+
+// Naming template syntax:
+//   {key}            value of a variable
+//   {key|text}       value, or "text" if it's empty
+//   {key:spec...}    value with specs applied in order:
+//                      03    zero-pad to 3 chars, 3 pads with spaces
+//                      .20   truncate to 20 chars
+//                      upper, lower, title
+//   [ ... ]          optional segment, dropped unless every variable in it is non-empty
+//   /                path separator; each component is sanitised separately
+//   \{ \} \[ \] \\   literal characters
+
+#[derive(Debug, Clone, PartialEq)]
+enum Spec {
+    Pad { width: usize, zero: bool },
+    Truncate(usize),
+    Upper,
+    Lower,
+    Title,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Var {
+    key: String,
+    specs: Vec<Spec>,
+    fallback: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Text(String),
+    Var(Var),
+    Optional(Vec<Node>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+fn parse_spec(spec: &str) -> Result<Spec, String> {
+    match spec {
+        "upper" => return Ok(Spec::Upper),
+        "lower" => return Ok(Spec::Lower),
+        "title" => return Ok(Spec::Title),
+        _ => {}
+    }
+    if let Some(n) = spec.strip_prefix('.') {
+        return n.parse()
+            .map(Spec::Truncate)
+            .map_err(|_| format!("invalid truncation \"{}\"", spec));
+    }
+    spec.parse()
+        .map(|width| Spec::Pad { width, zero: spec.starts_with('0') })
+        .map_err(|_| format!("unknown format \"{}\"", spec))
+}
+
+fn parse_var(body: &str) -> Result<Var, String> {
+    let (body, fallback) = match body.split_once('|') {
+        Some((b, f)) => (b, Some(f.to_string())),
+        None => (body, None),
+    };
+    let mut parts = body.split(':');
+    let key = parts.next().unwrap_or_default().trim().to_string();
+    if key.is_empty() {
+        return Err("empty placeholder".to_string());
+    }
+    let specs = parts.map(parse_spec).collect::<Result<Vec<Spec>, String>>()?;
+    Ok(Var { key, specs, fallback })
+}
+
+fn parse_nodes(chars: &mut Chars, in_optional: bool) -> Result<Vec<Node>, String> {
+    let mut nodes = Vec::new();
+    let mut text = String::new();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(escaped) => text.push(escaped),
+                None => return Err("template ends with \\".to_string()),
+            },
+            '{' => {
+                let mut body = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => body.push(c),
+                        None => return Err("unclosed {".to_string()),
+                    }
+                }
+                if !text.is_empty() {
+                    nodes.push(Node::Text(std::mem::take(&mut text)));
+                }
+                nodes.push(Node::Var(parse_var(&body)?));
+            }
+            '}' => return Err("unmatched }".to_string()),
+            '[' => {
+                if !text.is_empty() {
+                    nodes.push(Node::Text(std::mem::take(&mut text)));
+                }
+                nodes.push(Node::Optional(parse_nodes(chars, true)?));
+            }
+            ']' if in_optional => {
+                if !text.is_empty() {
+                    nodes.push(Node::Text(text));
+                }
+                return Ok(nodes);
+            }
+            ']' => return Err("unmatched ]".to_string()),
+            c => text.push(c),
+        }
+    }
+
+    if in_optional {
+        return Err("unclosed [".to_string());
+    }
+    if !text.is_empty() {
+        nodes.push(Node::Text(text));
+    }
+    Ok(nodes)
+}
+
+fn collect_keys<'a>(nodes: &'a [Node], keys: &mut Vec<&'a str>) {
+    for node in nodes {
+        match node {
+            Node::Var(v) => keys.push(&v.key),
+            Node::Optional(children) => collect_keys(children, keys),
+            Node::Text(_) => {}
+        }
+    }
+}
+
+fn title_case(s: &str) -> String {
+    s.split(' ')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars.flat_map(|c| c.to_lowercase())).collect(),
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn apply_spec(value: String, spec: &Spec) -> String {
+    match spec {
+        Spec::Pad { width, zero } => {
+            if *zero {
+                format!("{:0>width$}", value, width = width)
+            } else {
+                format!("{:>width$}", value, width = width)
+            }
+        }
+        Spec::Truncate(n) => value.chars().take(*n).collect::<String>().trim_end().to_string(),
+        Spec::Upper => value.to_uppercase(),
+        Spec::Lower => value.to_lowercase(),
+        Spec::Title => title_case(&value),
+    }
+}
+
+// Returns the rendered text and whether every variable in it had a value.
+fn render_nodes(nodes: &[Node], vars: &HashMap<&str, String>, policy: &FilenamePolicy) -> (String, bool) {
+    let mut out = String::new();
+    let mut complete = true;
+
+    for node in nodes {
+        match node {
+            Node::Text(t) => out.push_str(t),
+            Node::Var(v) => {
+                // Values can't introduce path separators, only the template can.
+                let value = vars.get(v.key.as_str())
+                    .map(|value| utils::sanitise_value(value, policy))
+                    .filter(|value| !value.is_empty());
+                match (value, &v.fallback) {
+                    (Some(value), _) => out.push_str(&v.specs.iter().fold(value, apply_spec)),
+                    (None, Some(fallback)) => out.push_str(fallback),
+                    (None, None) => complete = false,
+                }
+            }
+            Node::Optional(children) => {
+                let (text, child_complete) = render_nodes(children, vars, policy);
+                if child_complete {
+                    out.push_str(&text);
+                }
+            }
+        }
+    }
+    (out, complete)
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Template, String> {
+        let nodes = parse_nodes(&mut template.chars(), false)?;
+        Ok(Template { nodes })
+    }
+
+    pub fn keys(&self) -> Vec<&str> {
+        let mut keys = Vec::new();
+        collect_keys(&self.nodes, &mut keys);
+        keys
+    }
+
+    // Renders to a relative path, sanitising each component.
+    pub fn render(&self, vars: &HashMap<&str, String>, policy: &FilenamePolicy) -> PathBuf {
+        let (rendered, _) = render_nodes(&self.nodes, vars, policy);
+        let components: Vec<String> = rendered
+            .split(['/', '\\'])
+            .filter(|c| !c.trim().is_empty())
+            .map(|c| utils::sanitise(c, policy))
+            .collect();
+
+        if components.is_empty() {
+            return PathBuf::from(utils::sanitise("", policy));
+        }
+        components.iter().collect()
+    }
+}
+
+// Checks template syntax and that it only uses known variables.
+pub fn validate(name: &str, template: &str, known_keys: &[&str]) -> Result<(), Box<dyn Error>> {
+    let parsed = Template::parse(template)
+        .map_err(|e| format!("Invalid {}: {}", name, e))?;
+    for key in parsed.keys() {
+        if !known_keys.contains(&key) {
+            return Err(format!(
+                "Invalid {}: unknown placeholder {{{}}}. Available: {}",
+                name, key, known_keys.join(", "),
+            ).into());
+        }
+    }
+    Ok(())
+}
+
+pub fn uses_any(template: &str, keys: &[&str]) -> bool {
+    Template::parse(template).is_ok_and(|t| t.keys().iter().any(|k| keys.contains(k)))
+}
+
+// Templates are validated at startup, so a parse failure here can only come from
+// a template that was never checked; it renders as plain text.
+pub fn render(template: &str, vars: &HashMap<&str, String>, policy: &FilenamePolicy) -> PathBuf {
+    match Template::parse(template) {
+        Ok(parsed) => parsed.render(vars, policy),
+        Err(_) => PathBuf::from(utils::sanitise(template, policy)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{FilenameCharset, TranslitScheme};
+
+    fn policy() -> FilenamePolicy {
+        FilenamePolicy {
+            charset: FilenameCharset::Unicode,
+            translit_scheme: TranslitScheme::Gost,
+            replacement: "_".to_string(),
+            collapse_whitespace: false,
+            trim: true,
+        }
+    }
+
+    fn render_with(template: &str, vars: &[(&'static str, &str)]) -> PathBuf {
+        let vars: HashMap<&str, String> = vars.iter().map(|(k, v)| (*k, v.to_string())).collect();
+        render(template, &vars, &policy())
+    }
+
+    #[test]
+    fn parses_specs_and_fallbacks() {
+        let parsed = Template::parse("{track_num:02} {title|Untitled:x}").unwrap();
+        assert_eq!(parsed.nodes, vec![
+            Node::Var(Var {
+                key: "track_num".to_string(),
+                specs: vec![Spec::Pad { width: 2, zero: true }],
+                fallback: None,
+            }),
+            Node::Text(" ".to_string()),
+            Node::Var(Var {
+                key: "title".to_string(),
+                specs: vec![],
+                fallback: Some("Untitled:x".to_string()),
+            }),
+        ]);
+    }
+
+    #[test]
+    fn rejects_malformed_templates() {
+        for bad in ["{title", "title}", "[{title}", "{title}]", "{}", "{title:wide}", "{title:.x}", "end\\"] {
+            assert!(Template::parse(bad).is_err(), "{} should fail", bad);
+        }
+    }
+
+    #[test]
+    fn lists_keys_inside_optional_segments() {
+        let parsed = Template::parse("{album}[ ({year})]/{title}").unwrap();
+        assert_eq!(parsed.keys(), vec!["album", "year", "title"]);
+    }
+
+    #[test]
+    fn applies_specs_in_order() {
+        let path = render_with("-{n:3}-{n:03}-{t:.5:upper}-{t:title}", &[("n", "7"), ("t", "hello wORLD")]);
+        assert_eq!(path, PathBuf::from("-  7-007-HELLO-Hello World"));
+    }
+
+    #[test]
+    fn uses_fallback_for_missing_or_empty_values() {
+        assert_eq!(render_with("{genre|Unknown}", &[]), PathBuf::from("Unknown"));
+        assert_eq!(render_with("{genre|Unknown}", &[("genre", "")]), PathBuf::from("Unknown"));
+        assert_eq!(render_with("{genre|Unknown}", &[("genre", "Rock")]), PathBuf::from("Rock"));
+    }
+
+    #[test]
+    fn drops_optional_segments_with_missing_values() {
+        let template = "{album}[ ({year})][ \\[{edition}\\]]";
+        assert_eq!(render_with(template, &[("album", "A"), ("year", "2001")]), PathBuf::from("A (2001)"));
+        assert_eq!(render_with(template, &[("album", "A"), ("edition", "Deluxe")]), PathBuf::from("A [Deluxe]"));
+    }
+
+    #[test]
+    fn keeps_escaped_characters_literal() {
+        assert_eq!(render_with("\\{x\\} \\[{t}\\]", &[("t", "a")]), PathBuf::from("{x} [a]"));
+    }
+
+    #[test]
+    fn splits_on_template_slashes_only() {
+        let path = render_with("{artist}/{album}/{title}", &[("artist", "AC/DC"), ("album", "Back"), ("title", "Hells Bells")]);
+        assert_eq!(path, ["AC_DC", "Back", "Hells Bells"].iter().collect::<PathBuf>());
+    }
+
+    #[test]
+    fn skips_empty_path_components() {
+        let path = render_with("{artist}/[{album}]/{title}", &[("artist", "A"), ("title", "T")]);
+        assert_eq!(path, ["A", "T"].iter().collect::<PathBuf>());
+        assert_eq!(render_with("[{missing}]", &[]), PathBuf::from("noname"));
+    }
+}
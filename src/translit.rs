@@ -0,0 +1,157 @@
+use unicode_normalization::UnicodeNormalization;
+
+use crate::models::TranslitScheme;
+
+// GOST 7.79-2000 system B, the ASCII-only variant of ISO 9.
+fn gost_letter(c: char, next: Option<char>) -> Option<&'static str> {
+    let s = match c {
+        'а' => "a", 'б' => "b", 'в' => "v", 'г' => "g", 'д' => "d",
+        'е' => "e", 'ё' => "yo", 'ж' => "zh", 'з' => "z", 'и' => "i",
+        'й' => "j", 'к' => "k", 'л' => "l", 'м' => "m", 'н' => "n",
+        'о' => "o", 'п' => "p", 'р' => "r", 'с' => "s", 'т' => "t",
+        'у' => "u", 'ф' => "f", 'х' => "x", 'ч' => "ch", 'ш' => "sh",
+        'щ' => "shh", 'ъ' => "``", 'ы' => "y'", 'ь' => "`", 'э' => "e`",
+        'ю' => "yu", 'я' => "ya",
+        'і' => "i", 'ї' => "yi", 'є' => "ye", 'ґ' => "g`", 'ў' => "u`",
+        // "c" before е, и, ы, й, "cz" elsewhere.
+        'ц' => match next.map(|n| n.to_lowercase().next().unwrap_or(n)) {
+            Some('е' | 'и' | 'ы' | 'й' | 'і') => "c",
+            _ => "cz",
+        },
+        _ => return None,
+    };
+    Some(s)
+}
+
+// BGN/PCGN style, simplified for file names: no apostrophes for hard/soft signs.
+fn bgn_letter(c: char, _next: Option<char>) -> Option<&'static str> {
+    let s = match c {
+        'а' => "a", 'б' => "b", 'в' => "v", 'г' => "g", 'д' => "d",
+        'е' => "e", 'ё' => "yo", 'ж' => "zh", 'з' => "z", 'и' => "i",
+        'й' => "y", 'к' => "k", 'л' => "l", 'м' => "m", 'н' => "n",
+        'о' => "o", 'п' => "p", 'р' => "r", 'с' => "s", 'т' => "t",
+        'у' => "u", 'ф' => "f", 'х' => "kh", 'ц' => "ts", 'ч' => "ch",
+        'ш' => "sh", 'щ' => "shch", 'ъ' => "", 'ы' => "y", 'ь' => "",
+        'э' => "e", 'ю' => "yu", 'я' => "ya",
+        'і' => "i", 'ї' => "yi", 'є' => "ye", 'ґ' => "g", 'ў' => "w",
+        _ => return None,
+    };
+    Some(s)
+}
+
+// Transliterates Cyrillic letters to Latin, leaving everything else as is.
+// "Щука" -> "Shchuka", "ЩУКА" -> "SHCHUKA".
+pub fn transliterate(text: &str, scheme: TranslitScheme) -> String {
+    let chars: Vec<char> = text.nfc().collect();
+    let mut out = String::with_capacity(text.len());
+
+    for (i, &c) in chars.iter().enumerate() {
+        let lower = c.to_lowercase().next().unwrap_or(c);
+        let next = chars.get(i + 1).copied();
+        let mapped = match scheme {
+            TranslitScheme::Gost => gost_letter(lower, next),
+            TranslitScheme::Bgn => bgn_letter(lower, next),
+        };
+        let Some(latin) = mapped else {
+            out.push(c);
+            continue;
+        };
+
+        if lower == c {
+            out.push_str(latin);
+            continue;
+        }
+        // A capital inside an all-caps word is capitalised as a whole.
+        let neighbour_upper = next.filter(|n| n.is_alphabetic())
+            .or_else(|| i.checked_sub(1).map(|p| chars[p]).filter(|p| p.is_alphabetic()))
+            .is_some_and(|n| n.is_uppercase());
+        if neighbour_upper {
+            out.push_str(&latin.to_uppercase());
+        } else {
+            let mut latin_chars = latin.chars();
+            if let Some(first) = latin_chars.next() {
+                out.extend(first.to_uppercase());
+                out.push_str(latin_chars.as_str());
+            }
+        }
+    }
+    out
+}
+
+// Transliterates Cyrillic with the given scheme and everything else with deunicode's
+// tables. Characters without an ASCII form become `replacement`.
+pub fn to_ascii(text: &str, scheme: TranslitScheme, replacement: &str) -> String {
+    let latin = transliterate(text, scheme);
+    let mut out = String::with_capacity(latin.len());
+    for c in latin.chars() {
+        if c.is_ascii() {
+            out.push(c);
+        } else {
+            match deunicode::deunicode_char(c) {
+                Some(ascii) if !ascii.is_empty() => out.push_str(ascii),
+                _ => out.push_str(replacement),
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gost_uses_ascii_digraphs() {
+        let t = |s| transliterate(s, TranslitScheme::Gost);
+        assert_eq!(t("Щука"), "Shhuka");
+        assert_eq!(t("Хрущёв"), "Xrushhyov");
+        assert_eq!(t("объявление"), "ob``yavlenie");
+        assert_eq!(t("Мысль"), "My'sl`");
+        assert_eq!(t("Эхо"), "E`xo");
+        assert_eq!(t("Йошкар-Ола"), "Joshkar-Ola");
+    }
+
+    #[test]
+    fn gost_spells_tse_by_the_next_letter() {
+        let t = |s| transliterate(s, TranslitScheme::Gost);
+        assert_eq!(t("цирк"), "cirk");
+        assert_eq!(t("Цех"), "Cex");
+        assert_eq!(t("ЦИРК"), "CIRK");
+        assert_eq!(t("царь"), "czar`");
+        assert_eq!(t("конец"), "konecz");
+    }
+
+    #[test]
+    fn bgn_drops_signs() {
+        let t = |s| transliterate(s, TranslitScheme::Bgn);
+        assert_eq!(t("Щука"), "Shchuka");
+        assert_eq!(t("Хрущёв"), "Khrushchyov");
+        assert_eq!(t("объявление"), "obyavlenie");
+        assert_eq!(t("Мысль"), "Mysl");
+        assert_eq!(t("Цой"), "Tsoy");
+    }
+
+    #[test]
+    fn covers_ukrainian_and_belarusian_letters() {
+        assert_eq!(transliterate("Їжак Ґанок Єва Ўсё", TranslitScheme::Gost), "Yizhak G`anok Yeva U`syo");
+        assert_eq!(transliterate("Їжак Ґанок Єва Ўсё", TranslitScheme::Bgn), "Yizhak Ganok Yeva Wsyo");
+    }
+
+    #[test]
+    fn capitalises_whole_digraphs_in_caps() {
+        assert_eq!(transliterate("ЩУКА", TranslitScheme::Bgn), "SHCHUKA");
+        assert_eq!(transliterate("Щ", TranslitScheme::Bgn), "Shch");
+        assert_eq!(transliterate("ЖЖ", TranslitScheme::Gost), "ZHZH");
+    }
+
+    #[test]
+    fn leaves_other_scripts_alone() {
+        assert_eq!(transliterate("Кино – Café 東京", TranslitScheme::Bgn), "Kino – Café 東京");
+    }
+
+    #[test]
+    fn ascii_replaces_what_has_no_ascii_form() {
+        assert_eq!(to_ascii("Кино – Café", TranslitScheme::Bgn, "_"), "Kino - Cafe");
+        assert_eq!(to_ascii("a\u{E000}b", TranslitScheme::Gost, "_"), "a_b");
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use crate::api::client::YandexMusicClient;
+use crate::api::structs::AlbumResult;
+use crate::archive::DownloadArchive;
+use crate::models::{DownloadFormat, Settings};
+use crate::replace::{self, Replacement};
+use crate::retag;
+use crate::tags::YANDEX_TRACK_ID;
+use crate::{metadata, tags, utils};
+
+const REPLACED_DIR: &str = ".replaced";
+
+struct PlannedUpgrade {
+    path: PathBuf,
+    album_id: String,
+    track_id: String,
+}
+
+// Re-downloads library tracks saved below the configured format when Yandex now
+// offers something better. Old files are moved to .replaced in the library
+// folder, or deleted.
+pub fn upgrade_library(
+    c: &mut YandexMusicClient,
+    settings: &Settings,
+    archive: &mut DownloadArchive,
+    lib_path: &Path,
+    dry_run: bool,
+    assume_yes: bool,
+    delete_old: bool,
+) -> Result<(), Box<dyn Error>> {
+    let replaced_dir = lib_path.join(REPLACED_DIR);
+    let files: Vec<PathBuf> = utils::find_audio_files(lib_path)?
+        .into_iter()
+        .filter(|p| !p.starts_with(&replaced_dir))
+        .collect();
+    if files.is_empty() {
+        return Err("No audio files found in library".into());
+    }
+    println!("Found {} files, checking for better versions...", files.len());
+
+    let mut albums: HashMap<String, AlbumResult> = HashMap::new();
+    let mut planned = Vec::new();
+    let mut failed = 0;
+
+    for path in files {
+        match plan_upgrade(c, settings, archive, lib_path, &path, &mut albums) {
+            Ok(Some(p)) => planned.push(p),
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!("Could not check {:?}: {}", path, e);
+                failed += 1;
+            }
+        }
+    }
+
+    println!("\n{} files can be upgraded, {} could not be checked.", planned.len(), failed);
+    if planned.is_empty() || dry_run {
+        return Ok(());
+    }
+
+    let prompt = if delete_old { "Upgrade and delete old files?" } else { "Upgrade files?" };
+    if !assume_yes && !utils::confirm(prompt) {
+        println!("Aborted.");
+        return Ok(());
+    }
+
+    let mut covers: HashMap<String, Vec<u8>> = HashMap::new();
+    let total = planned.len();
+    for (i, p) in planned.iter().enumerate() {
+        println!("\nUpgrading {} of {}: {:?}", i + 1, total, p.path);
+        let replacement = Replacement {
+            path: &p.path,
+            album_id: &p.album_id,
+            track_id: &p.track_id,
+            retire_to: (!delete_old).then(|| {
+                replaced_dir.join(p.path.strip_prefix(lib_path).unwrap_or(&p.path))
+            }),
+            done_message: "Upgraded",
+        };
+        if let Err(e) = replace::replace_file(c, settings, archive, replacement, &mut albums, &mut covers) {
+            eprintln!("Upgrade failed: {}", e);
+        }
+        if settings.sleep && i + 1 < total {
+            thread::sleep(Duration::from_secs(2));
+        }
+    }
+    Ok(())
+}
+
+// Returns None if the file is already good enough or nothing better is offered.
+fn plan_upgrade(
+    c: &mut YandexMusicClient,
+    settings: &Settings,
+    archive: &DownloadArchive,
+    lib_path: &Path,
+    path: &Path,
+    albums: &mut HashMap<String, AlbumResult>,
+) -> Result<Option<PlannedUpgrade>, Box<dyn Error>> {
+    let path = path.to_path_buf();
+    let codec = path.extension()
+        .and_then(|e| e.to_str())
+        .and_then(metadata::parse_codec_from_ext)
+        .ok_or("unsupported file type")?;
+    let old_tags = tags::read_tags(&path, codec)?;
+
+    // The file itself is checked first; the archive only helps when it can't tell.
+    let current = tags::read_quality(&path, codec)
+        .or_else(|| old_tags.get(YANDEX_TRACK_ID).and_then(|track_id| archive.quality_of(track_id, &path)))
+        .ok_or("couldn't work out the file's quality")?;
+    if current >= settings.format {
+        return Ok(None);
+    }
+
+    let (album_id, track_id) = retag::identify_file(c, settings, lib_path, &path, &old_tags, albums)?;
+    let info = c.get_file_info(&track_id, settings.format.as_api_str())?;
+    let offered = metadata::parse_quality(&info.codec, info.bitrate as u32);
+    let Some((specs, file_ext)) = metadata::parse_specs(&info.codec, info.bitrate) else {
+        return Err(format!("Unknown codec returned: {}", info.codec).into());
+    };
+    if offered.is_none_or(|offered| offered <= current) {
+        println!("No better version of {:?} yet.", path);
+        return Ok(None);
+    }
+
+    let target = path.with_extension(&file_ext);
+    if target != path && target.exists() {
+        println!("Skipping {:?}, {:?} already exists.", path, target);
+        return Ok(None);
+    }
+
+    println!("{:?}: {} -> {}", path, describe(current), specs);
+    Ok(Some(PlannedUpgrade { path, album_id, track_id }))
+}
+
+fn describe(format: DownloadFormat) -> &'static str {
+    match format {
+        DownloadFormat::Aac64 => "AAC 64",
+        DownloadFormat::Aac192 => "AAC 192",
+        DownloadFormat::Hq => "AAC 256 / MP3 320",
+        DownloadFormat::Flac => "FLAC",
+    }
+}
@@ -2,22 +2,19 @@ use std::{env, fs, io};
 use std::collections::HashMap;
 use std::error::Error;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Error as IoError};
-use std::path::PathBuf;
+use std::io::{BufRead, BufReader, Error as IoError, Write};
+use std::path::{Path, PathBuf};
 use std::sync::OnceLock;
 use regex::{Regex};
+use unicode_normalization::UnicodeNormalization;
 use aes::cipher::{KeyIvInit, StreamCipher};
-use crate::models::{MediaLink, ParsedAlbumMeta};
+use crate::metadata;
+use crate::template;
+use crate::models::{FilenameCharset, FilenamePolicy, MediaLink, ParsedAlbumMeta};
+use crate::translit;
 
 type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
 
-// TODO: Remove
-const URLS_REGEX_STRINGS: [&str; 3] = [
-    r#"^https://music\.yandex\.(?:by|kz|ru)/album/(\d+)(?:/track/(\d+)(?:\?.+)?)?$"#,
-    r#"^https://music\.yandex\.(?:by|kz|ru)/playlists/((?:[a-z]{2}\.|)[a-z\d]{8}-[a-z\d]{4}-[a-z\d]{4}-[a-z\d]{4}-[a-z\d]{12})$"#,
-    r#"^https://music\.yandex\.(?:by|kz|ru)/artist/(\d+)(?:/albums)?(?:\?.+)?$"#,
-];
-
 pub fn get_exe_path() -> Result<PathBuf, Box<dyn Error>> {
     let exe_path = env::current_exe()?;
     let dir = exe_path.parent()
@@ -26,7 +23,7 @@ pub fn get_exe_path() -> Result<PathBuf, Box<dyn Error>> {
     Ok(dir)
 }
 
-pub fn resolve_ffmpeg_path(cfg_path: Option<PathBuf>, exe_dir: &PathBuf) -> PathBuf {
+pub fn resolve_ffmpeg_path(cfg_path: Option<PathBuf>, exe_dir: &Path) -> PathBuf {
     let local_name = if cfg!(target_os = "windows") { "ffmpeg.exe" } else { "ffmpeg" };
     let local_path = exe_dir.join(local_name);
     if local_path.exists() { return local_path; }
@@ -78,21 +75,59 @@ pub fn parse_url(url: &str) -> Option<MediaLink> {
     None
 }
 
-pub fn sanitise(filename: &str) -> String {
+fn illegal_chars_regex() -> &'static Regex {
     static RE: OnceLock<Regex> = OnceLock::new();
-    let re = RE.get_or_init(|| {
+    RE.get_or_init(|| {
         Regex::new(r#"[\\/:*?"><|]"#).unwrap()
-    });
+    })
+}
 
-    let sanitised = re.replace_all(filename, "_");
+fn whitespace_regex() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| {
+        Regex::new(r"\s+").unwrap()
+    })
+}
 
-    let result = sanitised.trim().trim_end_matches(".");
+// Turns one path component into a file or folder name according to the policy.
+pub fn sanitise(filename: &str, policy: &FilenamePolicy) -> String {
+    let converted = match policy.charset {
+        FilenameCharset::Unicode => filename.nfc().collect(),
+        FilenameCharset::Translit => translit::transliterate(filename, policy.translit_scheme),
+        FilenameCharset::Ascii => translit::to_ascii(filename, policy.translit_scheme, &policy.replacement),
+    };
+    let mut result = illegal_chars_regex().replace_all(&converted, policy.replacement.as_str()).to_string();
+
+    if policy.collapse_whitespace {
+        result = whitespace_regex().replace_all(&result, " ").to_string();
+    }
+    if policy.trim {
+        // Windows drops trailing dots and spaces, which breaks later lookups.
+        result = result.trim().trim_end_matches(['.', ' ']).to_string();
+    }
 
     if result.is_empty() {
         return "noname".to_string();
     }
 
-    result.to_string()
+    result
+}
+
+// Replaces illegal characters in a template value without trimming,
+// since the value may end up in the middle of a path component.
+pub fn sanitise_value(value: &str, policy: &FilenamePolicy) -> String {
+    illegal_chars_regex().replace_all(value, policy.replacement.as_str()).to_string()
+}
+
+// The replacement ends up in names, so it mustn't need replacing itself.
+pub fn validate_replacement(replacement: &str, charset: FilenameCharset) -> Result<(), Box<dyn Error>> {
+    if illegal_chars_regex().is_match(replacement) {
+        return Err("Invalid filename_replacement: contains characters not allowed in file names".into());
+    }
+    if charset == FilenameCharset::Ascii && !replacement.is_ascii() {
+        return Err("Invalid filename_replacement: must be ASCII with filename_charset = \"ascii\"".into());
+    }
+    Ok(())
 }
 
 pub fn decrypt_buff(
@@ -110,41 +145,101 @@ pub fn decrypt_buff(
     Ok(())
 }
 
-fn parse_template(template: &str, replacements: HashMap<&str, String>) -> String {
-    let mut result = template.to_string();
-
-    for (key, value) in replacements {
-        let placeholder = format!("{{{}}}", key);
-        result = result.replace(&placeholder, &value);
-    }
+pub const ALBUM_TEMPLATE_VARS: &[&str] = &[
+    "album_artist", "album_title", "album_id", "album_type", "label", "genre",
+    "year", "original_year", "release_date", "explicit",
+    "codec", "bitrate", "quality", "playlist_name",
+];
+pub const TRACK_ONLY_TEMPLATE_VARS: &[&str] = &[
+    "track_num", "track_num_pad", "track_total", "disc_num", "disc_total",
+    "title", "artist", "original_artist", "track_id", "playlist_pos",
+];
+pub const PLAYLIST_TEMPLATE_VARS: &[&str] = &["owner", "playlist_name"];
+// Album vars need a track's file info to be known.
+pub const FILE_INFO_TEMPLATE_VARS: &[&str] = &["codec", "bitrate", "quality"];
 
-    sanitise(&result)
+// Unknown numbers are 0, which should count as empty in templates.
+fn format_num(n: u16) -> String {
+    if n == 0 { String::new() } else { n.to_string() }
 }
 
-pub(crate) fn parse_album_template(template: &str, meta: &ParsedAlbumMeta) -> String {
-    let m: HashMap<&str, String> = HashMap::from([
+pub(crate) fn album_template_vars(meta: &ParsedAlbumMeta) -> HashMap<&'static str, String> {
+    HashMap::from([
         ("album_artist", meta.album_artist.clone()),
         ("album_title", meta.album_title.clone()),
+        ("album_id", meta.album_id.clone()),
+        ("album_type", meta.album_type.clone().unwrap_or_default()),
         ("label", meta.label.clone()),
+        ("genre", meta.genre.clone().unwrap_or_default()),
         ("year", meta.year.map(|y| y.to_string()).unwrap_or_default()),
+        ("original_year", meta.original_year.map(|y| y.to_string()).unwrap_or_default()),
+        ("release_date", meta.release_date.clone().unwrap_or_default()),
+        ("explicit", if meta.explicit { "E".to_string() } else { String::new() }),
+        ("codec", meta.codec.clone()),
+        ("bitrate", format_num(meta.bitrate)),
+        ("quality", meta.quality.clone()),
+        ("playlist_name", meta.playlist_name.clone()),
+    ])
+}
+
+// Track templates can use album vars too.
+pub(crate) fn track_template_vars(meta: &ParsedAlbumMeta, padding: &str) -> HashMap<&'static str, String> {
+    let mut m = album_template_vars(meta);
+    m.extend([
+        ("track_num", meta.track_num.to_string()),
+        ("track_num_pad", padding.to_string()),
+        ("track_total", format_num(meta.track_total)),
+        ("disc_num", format_num(meta.disc_num)),
+        ("disc_total", format_num(meta.disc_total)),
+        ("title", meta.title.clone()),
+        ("artist", meta.artist.clone()),
+        ("original_artist", meta.original_artist.clone()),
+        ("track_id", meta.track_id.clone()),
+        ("playlist_pos", format_num(meta.playlist_pos)),
     ]);
+    m
+}
 
-    parse_template(template, m)
+pub(crate) fn parse_album_template(template: &str, meta: &ParsedAlbumMeta, policy: &FilenamePolicy) -> PathBuf {
+    template::render(template, &album_template_vars(meta), policy)
 }
 
 pub(crate) fn parse_track_template(
     template: &str,
     meta: &ParsedAlbumMeta,
     padding: &str,
-) -> String {
-    let m: HashMap<&str, String> = HashMap::from([
-        ("track_num", meta.track_num.to_string()),
-        ("track_num_pad", padding.to_string()),
-        ("title", meta.title.clone()),
-        ("artist", meta.artist.clone()),
+    policy: &FilenamePolicy,
+) -> PathBuf {
+    template::render(template, &track_template_vars(meta, padding), policy)
+}
+
+pub(crate) fn parse_playlist_template(
+    template: &str,
+    owner: &str,
+    playlist_name: &str,
+    policy: &FilenamePolicy,
+) -> PathBuf {
+    let vars = HashMap::from([
+        ("owner", owner.to_string()),
+        ("playlist_name", playlist_name.to_string()),
     ]);
+    template::render(template, &vars, policy)
+}
+
+// Path of `target` relative to `base_dir`; both are expected to share a root.
+pub fn relative_path(base_dir: &Path, target: &Path) -> PathBuf {
+    let base: Vec<_> = base_dir.components().collect();
+    let target_parts: Vec<_> = target.components().collect();
+    let common = base.iter().zip(&target_parts).take_while(|(a, b)| a == b).count();
 
-    parse_template(template, m)
+    let mut rel = PathBuf::new();
+    for _ in common..base.len() {
+        rel.push("..");
+    }
+    for part in &target_parts[common..] {
+        rel.push(part);
+    }
+    rel
 }
 
 fn contains(lines: &[String], value: &str) -> bool {
@@ -174,7 +269,7 @@ fn read_text_file_lines(filename: &str) -> Result<Vec<String>, IoError> {
 
 pub fn clean_url(url: &str) -> String {
     let trimmed = url.trim();
-    let stripped = trimmed.strip_suffix('/').unwrap_or(&trimmed);
+    let stripped = trimmed.strip_suffix('/').unwrap_or(trimmed);
     stripped.to_string()
 }
 
@@ -184,10 +279,10 @@ pub fn process_urls(urls: &[String]) -> Result<Vec<String>, Box<dyn Error>> {
 
     for url in urls {
         if url.ends_with(".txt") {
-            if contains(&text_paths, &url) {
+            if contains(&text_paths, url) {
                 continue;
             }
-            let text_lines = read_text_file_lines(&url)?;
+            let text_lines = read_text_file_lines(url)?;
             for text_line in text_lines {
                 let cleaned_line = clean_url(&text_line);
                 if !contains(&processed, &cleaned_line) {
@@ -196,7 +291,7 @@ pub fn process_urls(urls: &[String]) -> Result<Vec<String>, Box<dyn Error>> {
             }
             text_paths.push(url.clone());
         } else {
-            let cleaned_line = clean_url(&url);
+            let cleaned_line = clean_url(url);
             if !contains(&processed, &cleaned_line) {
                 processed.push(cleaned_line);
             }
@@ -206,6 +301,91 @@ pub fn process_urls(urls: &[String]) -> Result<Vec<String>, Box<dyn Error>> {
     Ok(processed)
 }
 
+// Most filesystems cap a single name at 255 bytes (ext4) or UTF-16 units (NTFS).
+const MAX_COMPONENT_LEN: usize = 255;
+// Room kept under a folder for the files put in it.
+pub const DIR_RESERVE: usize = 64;
+// Components aren't shrunk below this to fit the whole path.
+const MIN_COMPONENT_LEN: usize = 24;
+const HASH_SUFFIX_LEN: usize = 9;
+
+pub fn default_max_path_len() -> usize {
+    if cfg!(target_os = "windows") { 259 } else { 4095 }
+}
+
+// Windows limits count UTF-16 units, everything else counts bytes.
+fn measured_len(s: &str) -> usize {
+    if cfg!(target_os = "windows") {
+        s.encode_utf16().count()
+    } else {
+        s.len()
+    }
+}
+
+// FNV-1a, stable across runs and Rust versions so shortened names stay the same.
+fn name_hash(s: &str) -> u32 {
+    s.bytes().fold(0x811c9dc5u32, |h, b| (h ^ b as u32).wrapping_mul(0x01000193))
+}
+
+// Cuts a name down to max_len, keeping its start (where track numbers usually are)
+// and adding a hash of the full name so different long names stay unique.
+fn shorten_component(name: &str, max_len: usize) -> String {
+    if measured_len(name) <= max_len {
+        return name.to_string();
+    }
+    let suffix = format!("~{:08x}", name_hash(name));
+    let budget = max_len.saturating_sub(HASH_SUFFIX_LEN);
+
+    let mut kept = String::new();
+    for c in name.chars() {
+        if measured_len(&kept) + measured_len(c.encode_utf8(&mut [0; 4])) > budget {
+            break;
+        }
+        kept.push(c);
+    }
+    let kept = kept.trim_end_matches([' ', '.']);
+    kept.to_string() + &suffix
+}
+
+// Joins a rendered template path onto base, shortening components so that each fits
+// the filesystem's name limit and the whole path fits max_path_len. `reserve` is room
+// kept for what will be appended to the last component, e.g. ".flac".
+pub fn fit_path(base: &Path, rel: &Path, reserve: usize, max_path_len: usize) -> PathBuf {
+    let mut parts: Vec<String> = rel.iter().map(|c| c.to_string_lossy().to_string()).collect();
+    let last = parts.len().saturating_sub(1);
+    for (i, part) in parts.iter_mut().enumerate() {
+        let limit = if i == last { MAX_COMPONENT_LEN - reserve } else { MAX_COMPONENT_LEN };
+        *part = shorten_component(part, limit);
+    }
+
+    // Shorten the file name first, then the folders closest to it.
+    let base_len = measured_len(&base.to_string_lossy());
+    for i in (0..parts.len()).rev() {
+        let total = base_len + reserve + parts.iter().map(|p| measured_len(p) + 1).sum::<usize>();
+        if total <= max_path_len {
+            break;
+        }
+        let len = measured_len(&parts[i]);
+        let target = len.saturating_sub(total - max_path_len).max(MIN_COMPONENT_LEN);
+        if target < len {
+            parts[i] = shorten_component(&parts[i], target);
+        }
+    }
+
+    let mut path = base.to_path_buf();
+    path.extend(parts);
+    path
+}
+
+// Unlike Path::with_extension this doesn't eat anything after a dot in the name,
+// e.g. "01. Title" -> "01. Title.flac".
+pub fn add_extension(base: &Path, ext: &str) -> PathBuf {
+    let mut file_name = base.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(ext);
+    base.with_file_name(file_name)
+}
+
 pub fn file_exists(file_path: &PathBuf) -> Result<bool, IoError> {
     match fs::metadata(file_path) {
         Ok(meta) => Ok(meta.is_file()),
@@ -220,7 +400,124 @@ pub fn file_exists(file_path: &PathBuf) -> Result<bool, IoError> {
 }
 
 
+pub fn find_audio_files(dir: &Path) -> Result<Vec<PathBuf>, IoError> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(find_audio_files(&path)?);
+        } else if path.extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| metadata::parse_codec_from_ext(e).is_some())
+        {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+pub fn confirm(prompt: &str) -> bool {
+    print!("{} [y/N] ", prompt);
+    let _ = io::stdout().flush();
+
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
 pub fn format_track_number(track_num: u16, track_total: u16) -> String {
     let padding = track_total.to_string().len();
     format!("{:0width$}", track_num, width = padding)
 }
+
+// A fresh, empty folder for tests that work with files.
+#[cfg(test)]
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("ym-dl-test-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_short_components() {
+        assert_eq!(shorten_component("01. Title", 255), "01. Title");
+    }
+
+    #[test]
+    fn shortens_to_budget_with_hash_suffix() {
+        let name = format!("01. {}", "a".repeat(300));
+        let short = shorten_component(&name, 100);
+        assert_eq!(measured_len(&short), 100);
+        assert!(short.starts_with("01. aaa"));
+        assert!(short.ends_with(&format!("~{:08x}", name_hash(&name))));
+        assert_eq!(short, shorten_component(&name, 100));
+    }
+
+    #[test]
+    fn distinct_long_names_stay_distinct() {
+        let a = format!("{}a", "x".repeat(300));
+        let b = format!("{}b", "x".repeat(300));
+        assert_ne!(shorten_component(&a, 50), shorten_component(&b, 50));
+    }
+
+    #[test]
+    fn never_splits_multibyte_characters() {
+        let name = "Щ".repeat(200);
+        let short = shorten_component(&name, 100);
+        assert!(measured_len(&short) <= 100);
+        let (kept, _) = short.rsplit_once('~').unwrap();
+        assert!(kept.chars().all(|c| c == 'Щ'));
+    }
+
+    #[test]
+    fn trims_trailing_dots_and_spaces_before_suffix() {
+        let name = format!("{}. {}", "a".repeat(40), "b".repeat(100));
+        let short = shorten_component(&name, 40 + 2 + HASH_SUFFIX_LEN);
+        assert_eq!(short, format!("{}~{:08x}", "a".repeat(40), name_hash(&name)));
+    }
+
+    #[test]
+    fn leaves_room_for_the_extension() {
+        let rel = PathBuf::from("b".repeat(300));
+        let fitted = fit_path(Path::new("lib"), &rel, 5, 4095);
+        let name = fitted.file_name().unwrap().to_string_lossy().to_string();
+        assert_eq!(measured_len(&name), MAX_COMPONENT_LEN - 5);
+    }
+
+    #[test]
+    fn shortens_file_name_before_folders() {
+        let rel: PathBuf = ["Artist".repeat(5), "Album".repeat(5), "Track".repeat(20)].iter().collect();
+        let base = Path::new("/music");
+        let max = 150;
+        let fitted = fit_path(base, &rel, 5, max);
+        assert!(measured_len(&fitted.to_string_lossy()) + 5 <= max);
+        let parts: Vec<String> = fitted.strip_prefix(base).unwrap().iter()
+            .map(|c| c.to_string_lossy().to_string())
+            .collect();
+        assert_eq!(parts[0], "Artist".repeat(5));
+        assert_eq!(parts[1], "Album".repeat(5));
+        assert!(parts[2].starts_with("TrackTrack"));
+    }
+
+    #[test]
+    fn keeps_components_above_the_minimum() {
+        let rel: PathBuf = ["a".repeat(100), "b".repeat(100)].iter().collect();
+        let fitted = fit_path(Path::new("/music"), &rel, 0, 60);
+        for part in fitted.strip_prefix("/music").unwrap() {
+            assert!(measured_len(&part.to_string_lossy()) >= MIN_COMPONENT_LEN);
+        }
+    }
+
+    #[test]
+    fn adds_extension_after_dots() {
+        assert_eq!(add_extension(Path::new("dir/01. Title"), "flac"), PathBuf::from("dir/01. Title.flac"));
+    }
+}
@@ -0,0 +1,526 @@
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use md5::{Digest, Md5};
+use sha2::Sha256;
+
+use crate::api::client::YandexMusicClient;
+use crate::api::structs::AlbumResult;
+use crate::archive::DownloadArchive;
+use crate::models::Settings;
+use crate::replace::{self, Replacement};
+use crate::tags::{YANDEX_ALBUM_ID, YANDEX_TRACK_ID};
+use crate::{metadata, retag, tags, utils};
+
+pub const MANIFEST_FILE: &str = "checksums.sha256";
+const BROKEN_DIR: &str = ".broken";
+// Yandex durations are rounded and encoders pad a little.
+const DURATION_TOLERANCE_MS: u64 = 2000;
+
+// Decodes or parses an audio file and checks its length against the expected one.
+// The error is a readable reason.
+pub fn verify_file(path: &Path, expected_ms: Option<u64>) -> Result<(), String> {
+    let ext = path.extension().and_then(|e| e.to_str()).unwrap_or_default().to_lowercase();
+    let duration_ms = match ext.as_str() {
+        "flac" => verify_flac(path),
+        "m4a" => verify_mp4(path),
+        "mp3" => verify_mp3(path),
+        _ => return Err("unsupported file type".to_string()),
+    }?;
+
+    if let Some(expected) = expected_ms.filter(|ms| *ms > 0) {
+        if duration_ms.abs_diff(expected) > DURATION_TOLERANCE_MS {
+            return Err(format!(
+                "duration is {}, expected {}",
+                format_ms(duration_ms), format_ms(expected),
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Decodes every frame, which checks the frame CRCs, and compares the decoded audio
+// with the STREAMINFO MD5 and sample count.
+fn verify_flac(path: &Path) -> Result<u64, String> {
+    let mut reader = claxon::FlacReader::open(path).map_err(|e| format!("not a valid FLAC: {}", e))?;
+    let info = reader.streaminfo();
+    let bytes_per_sample = info.bits_per_sample.div_ceil(8) as usize;
+
+    let mut md5 = Md5::new();
+    let mut samples: u64 = 0;
+    let mut buffer = Vec::new();
+    let mut bytes = Vec::new();
+    let mut blocks = reader.blocks();
+    loop {
+        let block = match blocks.read_next_or_eof(buffer) {
+            Ok(Some(block)) => block,
+            Ok(None) => break,
+            Err(e) => return Err(format!("bad frame after {} samples: {}", samples, e)),
+        };
+        // MD5 of the interleaved samples, little-endian, at the stream's sample width.
+        bytes.clear();
+        for i in 0..block.duration() {
+            for ch in 0..block.channels() {
+                bytes.extend_from_slice(&block.sample(ch, i).to_le_bytes()[..bytes_per_sample]);
+            }
+        }
+        md5.update(&bytes);
+        samples += block.duration() as u64;
+        buffer = block.into_buffer();
+    }
+
+    if let Some(expected) = info.samples.filter(|s| *s > 0) {
+        if samples != expected {
+            return Err(format!("truncated: {} of {} samples", samples, expected));
+        }
+    }
+    if info.md5sum != [0; 16] && md5.finalize()[..] != info.md5sum {
+        return Err("decoded audio doesn't match the STREAMINFO MD5".to_string());
+    }
+    Ok(samples * 1000 / info.sample_rate.max(1) as u64)
+}
+
+// Walks the top-level boxes: they must add up to the file size exactly, and the
+// file needs ftyp, moov and mdat.
+fn verify_mp4(path: &Path) -> Result<u64, String> {
+    let mut f = File::open(path).map_err(|e| e.to_string())?;
+    let len = f.metadata().map_err(|e| e.to_string())?.len();
+    let found = walk_mp4_boxes(&mut f, len)?;
+
+    for required in ["ftyp", "moov", "mdat"] {
+        if !found.iter().any(|n| n == required) {
+            return Err(format!("no '{}' box", required));
+        }
+    }
+    let tag = mp4ameta::Tag::read_from_path(path).map_err(|e| format!("unreadable: {}", e))?;
+    let duration = tag.duration().ok_or("no duration in 'mvhd'")?;
+    Ok(duration.as_millis() as u64)
+}
+
+// Returns the names of the top-level boxes in order.
+fn walk_mp4_boxes<R: Read + Seek>(f: &mut R, len: u64) -> Result<Vec<String>, String> {
+    let mut found = Vec::new();
+    let mut pos = 0;
+
+    while pos < len {
+        let mut header = [0u8; 8];
+        f.seek(SeekFrom::Start(pos)).and_then(|_| f.read_exact(&mut header))
+            .map_err(|_| format!("truncated box header at byte {}", pos))?;
+        let name = String::from_utf8_lossy(&header[4..]).to_string();
+        let size = match u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64 {
+            0 => len - pos,
+            1 => {
+                let mut large = [0u8; 8];
+                f.read_exact(&mut large).map_err(|_| format!("truncated box header at byte {}", pos))?;
+                u64::from_be_bytes(large)
+            }
+            size => size,
+        };
+        if size < 8 || pos.checked_add(size).is_none_or(|end| end > len) {
+            return Err(format!("'{}' box runs past the end of the file, truncated?", name));
+        }
+        found.push(name);
+        pos += size;
+    }
+    Ok(found)
+}
+
+// Where the audio starts, after any ID3v2 tag.
+fn id3v2_len(data: &[u8]) -> usize {
+    if data.len() < 10 || &data[..3] != b"ID3" {
+        return 0;
+    }
+    let size = data[6..10].iter().fold(0usize, |acc, b| (acc << 7) | (*b as usize & 0x7f));
+    let footer = if data[5] & 0x10 != 0 { 10 } else { 0 };
+    10 + size + footer
+}
+
+// Walks the MPEG audio frames between the ID3 tags. Every frame must start where
+// the previous one ended and the last one must be complete.
+fn verify_mp3(path: &Path) -> Result<u64, String> {
+    let data = fs::read(path).map_err(|e| e.to_string())?;
+    let mut pos = id3v2_len(&data);
+    let mut end = data.len();
+    if end >= pos + 128 && &data[end - 128..end - 125] == b"TAG" {
+        end -= 128;
+    }
+
+    let mut frames = 0;
+    let mut samples: u64 = 0;
+    let mut sample_rate = 0;
+    while pos + 4 <= end {
+        let Some(frame) = parse_mp3_frame(&data[pos..pos + 4]) else {
+            return Err(match frames {
+                0 => "no MPEG audio frames found".to_string(),
+                _ => format!("lost frame sync at byte {} after {} frames", pos, frames),
+            });
+        };
+        if pos + frame.len > end {
+            return Err(format!("last frame truncated after {} frames", frames));
+        }
+        frames += 1;
+        samples += frame.samples as u64;
+        sample_rate = frame.sample_rate;
+        pos += frame.len;
+    }
+    if frames == 0 {
+        return Err("no MPEG audio frames found".to_string());
+    }
+    Ok(samples * 1000 / sample_rate as u64)
+}
+
+// Bitrate of the first frame in Kbps. Yandex serves constant bitrate MP3s only.
+pub fn read_mp3_bitrate(path: &Path) -> Option<u32> {
+    let data = fs::read(path).ok()?;
+    let pos = id3v2_len(&data);
+    parse_mp3_frame(data.get(pos..pos + 4)?).map(|frame| frame.bitrate)
+}
+
+struct Mp3Frame {
+    len: usize,
+    bitrate: u32,
+    samples: u32,
+    sample_rate: u32,
+}
+
+// Layer III frame headers only, which is all Yandex serves.
+fn parse_mp3_frame(h: &[u8]) -> Option<Mp3Frame> {
+    const BITRATES_V1: [u32; 15] = [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320];
+    const BITRATES_V2: [u32; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+
+    if h[0] != 0xff || h[1] & 0xe0 != 0xe0 || (h[1] >> 1) & 0x03 != 0x01 {
+        return None;
+    }
+    let version = (h[1] >> 3) & 0x03; // 3 = MPEG 1, 2 = MPEG 2, 0 = MPEG 2.5
+    let bitrate_idx = (h[2] >> 4) as usize;
+    let rate_idx = ((h[2] >> 2) & 0x03) as usize;
+    if version == 1 || bitrate_idx == 0 || bitrate_idx == 15 || rate_idx == 3 {
+        return None;
+    }
+    let padding = ((h[2] >> 1) & 0x01) as u32;
+    let base_rate = [44100, 48000, 32000][rate_idx];
+
+    let (bitrate, sample_rate, samples, coef) = match version {
+        3 => (BITRATES_V1[bitrate_idx], base_rate, 1152, 144),
+        2 => (BITRATES_V2[bitrate_idx], base_rate / 2, 576, 72),
+        _ => (BITRATES_V2[bitrate_idx], base_rate / 4, 576, 72),
+    };
+    Some(Mp3Frame {
+        len: (coef * bitrate * 1000 / sample_rate + padding) as usize,
+        bitrate,
+        samples,
+        sample_rate,
+    })
+}
+
+fn format_ms(ms: u64) -> String {
+    format!("{}:{:02}", ms / 60000, ms / 1000 % 60)
+}
+
+fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+// Writes checksums.sha256 for the audio files directly in `dir`, in the format
+// sha256sum -c reads.
+pub fn write_manifest(dir: &Path) -> Result<(), Box<dyn Error>> {
+    let mut lines = String::new();
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.is_file() && is_audio(p))
+        .collect();
+    files.sort();
+    for path in files {
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        lines.push_str(&format!("{}  {}\n", sha256_file(&path)?, name));
+    }
+    fs::write(dir.join(MANIFEST_FILE), lines)?;
+    Ok(())
+}
+
+// Re-hashes a file this tool rewrote, so the manifest of its folder doesn't report
+// it as changed. `previous` is the file it replaced, which may have had another name.
+// Folders without a manifest, or whose manifest doesn't list the file, are left alone.
+pub fn update_manifest(path: &Path, previous: &Path) -> Result<(), Box<dyn Error>> {
+    let Some(dir) = path.parent() else {
+        return Ok(());
+    };
+    let manifest = dir.join(MANIFEST_FILE);
+    if !manifest.exists() {
+        return Ok(());
+    }
+    let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let previous_name = previous.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let mut lines = String::new();
+    let mut updated = false;
+    for line in fs::read_to_string(&manifest)?.lines() {
+        match line.split_once("  ") {
+            Some((_, listed)) if listed == name || listed == previous_name => {
+                if !updated {
+                    lines.push_str(&format!("{}  {}\n", sha256_file(path)?, name));
+                    updated = true;
+                }
+            }
+            _ => lines.push_str(&format!("{}\n", line)),
+        }
+    }
+    if updated {
+        fs::write(manifest, lines)?;
+    }
+    Ok(())
+}
+
+// Returns (file name, problem) for each file that doesn't match the manifest in `dir`.
+fn check_manifest(dir: &Path) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let mut problems = Vec::new();
+    for line in fs::read_to_string(dir.join(MANIFEST_FILE))?.lines() {
+        let Some((hash, name)) = line.split_once("  ") else {
+            continue;
+        };
+        let path = dir.join(name);
+        if !path.exists() {
+            problems.push((name.to_string(), "missing".to_string()));
+            continue;
+        }
+        if sha256_file(&path)? != hash.to_lowercase() {
+            problems.push((name.to_string(), "checksum differs from manifest".to_string()));
+        }
+    }
+    Ok(problems)
+}
+
+fn is_audio(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .and_then(metadata::parse_codec_from_ext)
+        .is_some()
+}
+
+// Checks every file in a library and every checksum manifest in it. Broken files
+// with embedded IDs are downloaded again when `redownload` is set, and manifests
+// are (re)written for folders that pass when `write_manifests` is.
+pub fn verify_library(
+    c: &mut YandexMusicClient,
+    settings: &Settings,
+    archive: &mut DownloadArchive,
+    lib_path: &Path,
+    redownload: bool,
+    write_manifests: bool,
+) -> Result<(), Box<dyn Error>> {
+    // Skips .trash, .replaced, .broken and the like.
+    let files: Vec<PathBuf> = utils::find_audio_files(lib_path)?
+        .into_iter()
+        .filter(|p| {
+            !p.strip_prefix(lib_path).unwrap_or(p).components()
+                .any(|part| part.as_os_str().to_string_lossy().starts_with('.'))
+        })
+        .collect();
+    if files.is_empty() {
+        return Err("No audio files found in library".into());
+    }
+    println!("Verifying {} files...", files.len());
+
+    let mut albums: HashMap<String, AlbumResult> = HashMap::new();
+    let mut broken: Vec<(PathBuf, String)> = Vec::new();
+    // Folder -> whether everything in it passed.
+    let mut folders: BTreeMap<PathBuf, bool> = BTreeMap::new();
+
+    for (i, path) in files.iter().enumerate() {
+        let expected_ms = expected_duration(c, settings, path, &mut albums);
+        let result = verify_file(path, expected_ms);
+        let folder_ok = folders.entry(path.parent().unwrap_or(lib_path).to_path_buf()).or_insert(true);
+        if let Err(reason) = result {
+            println!("[{}/{}] FAILED {:?}: {}", i + 1, files.len(), path, reason);
+            *folder_ok = false;
+            broken.push((path.clone(), reason));
+        }
+    }
+
+    let mut mismatched = 0;
+    for (dir, ok) in folders.iter_mut() {
+        if !dir.join(MANIFEST_FILE).exists() {
+            continue;
+        }
+        let problems = match check_manifest(dir) {
+            Ok(problems) => problems,
+            Err(e) => vec![(MANIFEST_FILE.to_string(), format!("couldn't be checked: {}", e))],
+        };
+        for (name, problem) in problems {
+            println!("MANIFEST {:?}: {}", dir.join(name), problem);
+            mismatched += 1;
+            *ok = false;
+        }
+    }
+
+    println!(
+        "\n{} files checked, {} broken, {} manifest mismatches.",
+        files.len(), broken.len(), mismatched,
+    );
+
+    if write_manifests {
+        let mut written = 0;
+        for (dir, _) in folders.iter().filter(|(_, ok)| **ok) {
+            match write_manifest(dir) {
+                Ok(()) => written += 1,
+                Err(e) => eprintln!("Failed to write manifest in {:?}: {}", dir, e),
+            }
+        }
+        println!("Wrote {} checksum manifests.", written);
+    }
+
+    if redownload && !broken.is_empty() {
+        redownload_broken(c, settings, archive, lib_path, &broken, &mut albums)?;
+    }
+    Ok(())
+}
+
+fn expected_duration(
+    c: &mut YandexMusicClient,
+    settings: &Settings,
+    path: &Path,
+    albums: &mut HashMap<String, AlbumResult>,
+) -> Option<u64> {
+    let codec = path.extension().and_then(|e| e.to_str()).and_then(metadata::parse_codec_from_ext)?;
+    let summary = tags::read_tags(&path.to_path_buf(), codec).ok()?;
+    let (album_id, track_id) = (summary.get(YANDEX_ALBUM_ID)?, summary.get(YANDEX_TRACK_ID)?);
+    let album = retag::get_album_cached(c, settings, album_id, albums).ok()?;
+    metadata::find_track(album, track_id).and_then(|(_, _, _, track)| track.duration_ms)
+}
+
+// Broken files keep their place: the new download replaces them and the old file
+// is moved to .broken in the library folder.
+fn redownload_broken(
+    c: &mut YandexMusicClient,
+    settings: &Settings,
+    archive: &mut DownloadArchive,
+    lib_path: &Path,
+    broken: &[(PathBuf, String)],
+    albums: &mut HashMap<String, AlbumResult>,
+) -> Result<(), Box<dyn Error>> {
+    let settings = Settings { verify_downloads: true, ..settings.clone() };
+    let mut covers = HashMap::new();
+    println!("\nDownloading {} broken files again...", broken.len());
+
+    for (path, _) in broken {
+        let codec = path.extension().and_then(|e| e.to_str()).and_then(metadata::parse_codec_from_ext);
+        // Tags are written after muxing, so they usually survive whatever broke the audio.
+        let old_tags = codec.and_then(|codec| tags::read_tags(path, codec).ok()).unwrap_or_default();
+        let (Some(album_id), Some(track_id)) = (old_tags.get(YANDEX_ALBUM_ID), old_tags.get(YANDEX_TRACK_ID)) else {
+            eprintln!("No embedded IDs, can't download again: {:?}", path);
+            continue;
+        };
+        let replacement = Replacement {
+            path,
+            album_id,
+            track_id,
+            retire_to: Some(lib_path.join(BROKEN_DIR).join(path.strip_prefix(lib_path).unwrap_or(path))),
+            done_message: "Repaired",
+        };
+        if let Err(e) = replace::replace_file(c, &settings, archive, replacement, albums, &mut covers) {
+            eprintln!("Failed to download {:?} again: {}", path, e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn mp4_box(name: &[u8; 4], body_len: usize) -> Vec<u8> {
+        let mut data = ((8 + body_len) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(name);
+        data.resize(8 + body_len, 0);
+        data
+    }
+
+    fn walk(data: &[u8]) -> Result<Vec<String>, String> {
+        walk_mp4_boxes(&mut Cursor::new(data), data.len() as u64)
+    }
+
+    #[test]
+    fn parses_mpeg1_frames() {
+        let frame = parse_mp3_frame(&[0xff, 0xfb, 0x90, 0x00]).unwrap();
+        assert_eq!((frame.bitrate, frame.sample_rate, frame.samples, frame.len), (128, 44100, 1152, 417));
+        let padded = parse_mp3_frame(&[0xff, 0xfb, 0x92, 0x00]).unwrap();
+        assert_eq!(padded.len, 418);
+        let high = parse_mp3_frame(&[0xff, 0xfb, 0xe4, 0x00]).unwrap();
+        assert_eq!((high.bitrate, high.sample_rate, high.len), (320, 48000, 960));
+    }
+
+    #[test]
+    fn parses_mpeg2_and_2_5_frames() {
+        let v2 = parse_mp3_frame(&[0xff, 0xf3, 0x90, 0x00]).unwrap();
+        assert_eq!((v2.bitrate, v2.sample_rate, v2.samples, v2.len), (80, 22050, 576, 261));
+        let v25 = parse_mp3_frame(&[0xff, 0xe3, 0x90, 0x00]).unwrap();
+        assert_eq!((v25.bitrate, v25.sample_rate, v25.len), (80, 11025, 522));
+    }
+
+    #[test]
+    fn rejects_invalid_frame_headers() {
+        let invalid: [[u8; 4]; 6] = [
+            [0x00, 0xfb, 0x90, 0x00], // no sync
+            [0xff, 0xfd, 0x90, 0x00], // layer II
+            [0xff, 0xeb, 0x90, 0x00], // reserved version
+            [0xff, 0xfb, 0x00, 0x00], // free bitrate
+            [0xff, 0xfb, 0xf0, 0x00], // bad bitrate
+            [0xff, 0xfb, 0x9c, 0x00], // reserved sample rate
+        ];
+        for h in invalid {
+            assert!(parse_mp3_frame(&h).is_none(), "{:02x?} should be rejected", h);
+        }
+    }
+
+    #[test]
+    fn measures_id3v2_tags() {
+        assert_eq!(id3v2_len(b"\xff\xfb\x90\x00"), 0);
+        assert_eq!(id3v2_len(b"ID3\x04\x00\x00\x00\x00\x02\x01"), 10 + 257);
+        assert_eq!(id3v2_len(b"ID3\x04\x00\x10\x00\x00\x00\x10"), 10 + 16 + 10);
+    }
+
+    #[test]
+    fn walks_top_level_boxes() {
+        let data = [mp4_box(b"ftyp", 12), mp4_box(b"moov", 40), mp4_box(b"mdat", 100)].concat();
+        assert_eq!(walk(&data).unwrap(), vec!["ftyp", "moov", "mdat"]);
+    }
+
+    #[test]
+    fn handles_large_and_open_ended_boxes() {
+        let mut large = 1u32.to_be_bytes().to_vec();
+        large.extend_from_slice(b"mdat");
+        large.extend_from_slice(&32u64.to_be_bytes());
+        large.resize(32, 0);
+        let mut open = 0u32.to_be_bytes().to_vec();
+        open.extend_from_slice(b"free");
+        open.resize(20, 0);
+        let data = [mp4_box(b"ftyp", 8), large, open].concat();
+        assert_eq!(walk(&data).unwrap(), vec!["ftyp", "mdat", "free"]);
+    }
+
+    #[test]
+    fn reports_truncated_boxes() {
+        let mut data = [mp4_box(b"ftyp", 8), mp4_box(b"mdat", 100)].concat();
+        data.truncate(60);
+        assert!(walk(&data).unwrap_err().contains("'mdat' box runs past the end"));
+
+        let mut data = mp4_box(b"ftyp", 8);
+        data.extend_from_slice(&[0, 0, 0]);
+        assert!(walk(&data).unwrap_err().contains("truncated box header at byte 16"));
+
+        let mut data = mp4_box(b"ftyp", 8);
+        data[3] = 4;
+        assert!(walk(&data).is_err());
+
+        let mut huge = 1u32.to_be_bytes().to_vec();
+        huge.extend_from_slice(b"mdat");
+        huge.extend_from_slice(&u64::MAX.to_be_bytes());
+        let data = [mp4_box(b"ftyp", 8), huge].concat();
+        assert!(walk(&data).unwrap_err().contains("'mdat' box runs past the end"));
+    }
+}
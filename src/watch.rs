@@ -0,0 +1,221 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::hash::BuildHasher;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::api::client::YandexMusicClient;
+use crate::archive::DownloadArchive;
+use crate::models::{MediaLink, Settings, SourceKind};
+use crate::playlists::PlaylistEntry;
+use crate::{processor, utils};
+
+const STATE_FILE: &str = ".ym-watch.json";
+// Intervals vary by up to this fraction either way so polls don't line up.
+const JITTER: f64 = 0.1;
+
+#[derive(Serialize, Deserialize, Default)]
+struct WatchState {
+    sources: HashMap<String, SourceState>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct SourceState {
+    seen: HashSet<String>,
+    last_checked: u64,
+}
+
+struct NewItem {
+    id: String,
+    title: String,
+}
+
+pub struct WatchOptions<'a> {
+    pub subscriptions: &'a Path,
+    pub once: bool,
+    pub download_existing: bool,
+}
+
+// Polls the artists and playlists in a subscription file and downloads anything
+// released or added since the last poll. The first poll of a source only records
+// what's there, unless download_existing is set.
+pub fn watch(
+    c: &mut YandexMusicClient,
+    settings: &Settings,
+    archive: &mut DownloadArchive,
+    opts: &WatchOptions,
+) -> Result<(), Box<dyn Error>> {
+    let sources = read_subscriptions(opts.subscriptions)?;
+    if sources.is_empty() {
+        return Err("No artists or playlists in the subscription file".into());
+    }
+    let state_path = settings.watch_state.clone()
+        .unwrap_or_else(|| settings.out_path.join(STATE_FILE));
+    let mut state = load_state(&state_path)?;
+    println!("Watching {} sources, state in {:?}.", sources.len(), state_path);
+
+    loop {
+        for (i, link) in sources.iter().enumerate() {
+            let key = source_key(link);
+            let first_poll = !state.sources.contains_key(&key);
+            let source = state.sources.entry(key.clone()).or_default();
+
+            match poll_source(c, settings, archive, link, source, first_poll && !opts.download_existing) {
+                Ok(new_items) if !new_items.is_empty() => {
+                    println!("{}: {} new.", key, new_items.len());
+                    if let Some(url) = &settings.watch_webhook {
+                        if let Err(e) = fire_webhook(url, &key, &new_items) {
+                            eprintln!("Warning: Webhook failed: {}", e);
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("Error polling {}: {}", key, e),
+            }
+            source.last_checked = unix_now();
+            save_state(&state_path, &state)?;
+
+            if i + 1 < sources.len() {
+                thread::sleep(jittered(Duration::from_secs(if settings.sleep { 5 } else { 2 })));
+            }
+        }
+
+        if opts.once {
+            return Ok(());
+        }
+        let wait = jittered(Duration::from_secs(settings.watch_interval * 60));
+        println!("\nNext poll in {} min.", wait.as_secs() / 60);
+        thread::sleep(wait);
+    }
+}
+
+// Returns what was new; when `baseline` is set it's only recorded, not downloaded.
+fn poll_source(
+    c: &mut YandexMusicClient,
+    settings: &Settings,
+    archive: &mut DownloadArchive,
+    link: &MediaLink,
+    source: &mut SourceState,
+    baseline: bool,
+) -> Result<Vec<NewItem>, Box<dyn Error>> {
+    let mut new_items = Vec::new();
+
+    match link {
+        MediaLink::Artist { artist_id } => {
+            let meta = c.get_artist_meta(artist_id)?;
+            let artist_path = processor::artist_path(settings, &meta.artist.name);
+            for album in meta.albums {
+                let album_id = album.id.to_string();
+                if source.seen.contains(&album_id) {
+                    continue;
+                }
+                if !baseline {
+                    println!("\nNew release by {}: {}", meta.artist.name, album.title);
+                    if let Err(e) = processor::process_album(c, settings, archive, SourceKind::Artist, &album_id, None, Some(&artist_path)) {
+                        eprintln!("Failed to process album ID {}: {}", album_id, e);
+                        continue;
+                    }
+                    new_items.push(NewItem { id: album_id.clone(), title: album.title.clone() });
+                }
+                source.seen.insert(album_id);
+            }
+        }
+        MediaLink::Playlist { uuid_or_login } => {
+            let meta = processor::get_public_playlist(c, uuid_or_login)?;
+            let new_tracks: Vec<(String, String)> = meta.tracks.iter()
+                .filter(|t| !source.seen.contains(&t.track.id))
+                .map(|t| (t.track.id.clone(), t.track.title.clone()))
+                .collect();
+            if baseline {
+                source.seen.extend(new_tracks.into_iter().map(|(id, _)| id));
+            } else if !new_tracks.is_empty() {
+                println!("\n{} new tracks in {}", new_tracks.len(), meta.title);
+                let only: HashSet<String> = new_tracks.iter().map(|(id, _)| id.clone()).collect();
+                let entries = processor::process_playlist_tracks(c, settings, archive, meta, Some(&only))?;
+                // Skipped and failed tracks aren't marked as seen, so they're retried next poll.
+                for ((id, title), entry) in new_tracks.into_iter().zip(entries) {
+                    if let PlaylistEntry::Track { .. } = entry {
+                        new_items.push(NewItem { id: id.clone(), title });
+                        source.seen.insert(id);
+                    }
+                }
+            }
+        }
+        _ => return Err("only artists and playlists can be watched".into()),
+    }
+    Ok(new_items)
+}
+
+fn read_subscriptions(path: &Path) -> Result<Vec<MediaLink>, Box<dyn Error>> {
+    let mut sources = Vec::new();
+    for line in fs::read_to_string(path)?.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match utils::parse_url(&utils::clean_url(line)) {
+            Some(link @ (MediaLink::Artist { .. } | MediaLink::Playlist { .. })) => {
+                if !sources.contains(&link) {
+                    sources.push(link);
+                }
+            }
+            _ => eprintln!("Warning: Skipping subscription, not an artist or playlist URL: {}", line),
+        }
+    }
+    Ok(sources)
+}
+
+fn source_key(link: &MediaLink) -> String {
+    match link {
+        MediaLink::Artist { artist_id } => format!("artist:{}", artist_id),
+        MediaLink::Playlist { uuid_or_login } => format!("playlist:{}", uuid_or_login),
+        MediaLink::Album { album_id } => format!("album:{}", album_id),
+        MediaLink::Track { track_id, .. } => format!("track:{}", track_id),
+    }
+}
+
+fn load_state(path: &PathBuf) -> Result<WatchState, Box<dyn Error>> {
+    if !path.exists() {
+        return Ok(WatchState::default());
+    }
+    Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+}
+
+// Written after every source so an interrupted poll doesn't download things twice.
+fn save_state(path: &PathBuf, state: &WatchState) -> Result<(), Box<dyn Error>> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(state)?)?;
+    Ok(())
+}
+
+fn fire_webhook(url: &str, source: &str, items: &[NewItem]) -> Result<(), Box<dyn Error>> {
+    let body = json!({
+        "source": source,
+        "new": items.iter().map(|i| json!({ "id": i.id, "title": i.title })).collect::<Vec<_>>(),
+    });
+    reqwest::blocking::Client::new()
+        .post(url)
+        .json(&body)
+        .timeout(Duration::from_secs(10))
+        .send()?
+        .error_for_status()?;
+    Ok(())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+// No rand dependency; the randomly keyed std hasher is random enough for jitter.
+fn jittered(base: Duration) -> Duration {
+    let r = RandomState::new().hash_one(SystemTime::now()) as f64 / u64::MAX as f64;
+    base.mul_f64(1.0 - JITTER + 2.0 * JITTER * r)
+}